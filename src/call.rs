@@ -0,0 +1,286 @@
+//! nurse-call meaning of the GPIO pins
+//!
+//! GPIO push data is assumed to be `pin, level` pairs, pin commands sent by the tester are
+//! [`PinCommand`]s. Only pins going active count, the pin map of the device
+//! (`[calls.<table>]` in the registry) gives their meaning.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Args;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    confirm::PinCommand,
+    registry::{self, Mac},
+};
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct CallArgs {
+    /// write the per-bed call timeline and response times to this JSON file at exit
+    #[arg(long, requires = "esp_test")]
+    pub calls: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CallEvent {
+    /// call placed (cord, call button)
+    Call,
+    /// staff at the bed
+    Assist,
+    /// call cleared
+    Clear,
+    Tamper,
+}
+
+impl Display for CallEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallEvent::Call => "call placed",
+            CallEvent::Assist => "assist",
+            CallEvent::Clear => "cleared",
+            CallEvent::Tamper => "tamper",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Source {
+    /// GPIO push from the device
+    Push,
+    /// pin command sent by the tester
+    Command,
+}
+
+pub(crate) fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct TimelineEntry {
+    at: f64,
+    pin: u8,
+    event: CallEvent,
+    source: Source,
+}
+
+/// a call from being placed until it is cleared, times in seconds
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct Call {
+    placed: f64,
+    pin: u8,
+    opened_by: CallEvent,
+    // call to assist
+    assist: Option<f64>,
+    // call to clear
+    clear: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct ResponseStats {
+    pub count: usize,
+    pub mean: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl ResponseStats {
+    fn new(times: impl Iterator<Item = f64>) -> Self {
+        let times: Vec<f64> = times.collect();
+        Self {
+            count: times.len(),
+            mean: (!times.is_empty()).then(|| times.iter().sum::<f64>() / times.len() as f64),
+            max: times.iter().copied().reduce(f64::max),
+        }
+    }
+}
+
+fn secs(t: Option<f64>) -> String {
+    t.map_or_else(|| "-".to_string(), |t| format!("{t:.1}"))
+}
+
+/// calls and events of one bed
+#[derive(Debug, Default)]
+pub(crate) struct BedTimeline {
+    events: Vec<TimelineEntry>,
+    calls: Vec<Call>,
+    tampers: u32,
+}
+
+impl BedTimeline {
+    fn open_call(&mut self) -> Option<&mut Call> {
+        self.calls.last_mut().filter(|call| call.clear.is_none())
+    }
+
+    pub fn on_event(&mut self, at: f64, pin: u8, event: CallEvent, source: Source) {
+        self.events.push(TimelineEntry {
+            at,
+            pin,
+            event,
+            source,
+        });
+        match (event, self.open_call()) {
+            // a second cord while the call is open is the same call
+            (CallEvent::Call, Some(_)) => (),
+            (CallEvent::Call | CallEvent::Assist, None) => self.calls.push(Call {
+                placed: at,
+                pin,
+                opened_by: event,
+                assist: None,
+                clear: None,
+            }),
+            (CallEvent::Assist, Some(call)) => {
+                call.assist.get_or_insert(at - call.placed);
+            }
+            (CallEvent::Clear, Some(call)) => call.clear = Some(at - call.placed),
+            (CallEvent::Clear, None) => (),
+            (CallEvent::Tamper, _) => self.tampers += 1,
+        }
+    }
+
+    /// call to assist, over calls that were placed and then assisted
+    pub fn assist_stats(&self) -> ResponseStats {
+        ResponseStats::new(self.calls.iter().filter_map(|c| c.assist))
+    }
+
+    /// call to clear
+    pub fn clear_stats(&self) -> ResponseStats {
+        ResponseStats::new(self.calls.iter().filter_map(|c| c.clear))
+    }
+}
+
+#[derive(Serialize)]
+struct BedReport<'a> {
+    name: String,
+    mac: String,
+    tampers: u32,
+    assist: ResponseStats,
+    clear: ResponseStats,
+    calls: &'a [Call],
+    events: &'a [TimelineEntry],
+}
+
+/// call timelines of every bed with a pin map
+#[derive(Debug, Default)]
+pub(crate) struct CallTracker {
+    beds: BTreeMap<Mac, BedTimeline>,
+}
+
+impl CallTracker {
+    fn on_pin(&mut self, mac: &Mac, pin: u8, source: Source, at: f64) {
+        let Some(device) = registry::get(mac) else {
+            return;
+        };
+        let Some(event) = device.calls.get(&pin).copied() else {
+            debug!("CALL: {} pin {pin} has no call meaning", device.name);
+            return;
+        };
+        info!("CALL: {} {event} pin {pin} ({source:?})", device.name);
+        self.beds
+            .entry(*mac)
+            .or_default()
+            .on_event(at, pin, event, source);
+    }
+
+    /// `pin, level` pairs of a GPIO push
+    pub fn on_gpio(&mut self, mac: &Mac, data: &[u8], at: f64) {
+        for pair in data.chunks_exact(2).filter(|pair| pair[1] != 0) {
+            self.on_pin(mac, pair[0], Source::Push, at);
+        }
+    }
+
+    /// pin commands switching a pin on or pulsing it count
+    pub fn on_command(&mut self, command: &PinCommand, at: f64) {
+        if command.is_active() {
+            self.on_pin(&command.mac, command.pin, Source::Command, at);
+        }
+    }
+
+    /// one line per bed with its calls and response times in seconds
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{:>14} {:>5} {:>4} {:>6} {:>7} {:>7} {:>7} {:>7}",
+            "BED", "CALLS", "OPEN", "TAMPER", "ASSIST", "MAX", "CLEAR", "MAX"
+        )];
+        for (mac, bed) in &self.beds {
+            let (assist, clear) = (bed.assist_stats(), bed.clear_stats());
+            lines.push(format!(
+                "{:>14} {:>5} {:>4} {:>6} {:>7} {:>7} {:>7} {:>7}",
+                registry::name(mac).unwrap_or_else(|| hex::encode(mac)),
+                bed.calls.len(),
+                bed.calls.len() - clear.count,
+                bed.tampers,
+                secs(assist.mean),
+                secs(assist.max),
+                secs(clear.mean),
+                secs(clear.max),
+            ));
+        }
+        lines
+    }
+
+    pub fn export(&self, path: &Path) -> std::io::Result<()> {
+        let report: Vec<BedReport> = self
+            .beds
+            .iter()
+            .map(|(mac, bed)| BedReport {
+                name: registry::name(mac).unwrap_or_default(),
+                mac: hex::encode(mac),
+                tampers: bed.tampers,
+                assist: bed.assist_stats(),
+                clear: bed.clear_stats(),
+                calls: &bed.calls,
+                events: &bed.events,
+            })
+            .collect();
+        std::fs::write(path, serde_json::to_string_pretty(&report).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_call_timeline() {
+        let mut bed = BedTimeline::default();
+        bed.on_event(100.0, 2, CallEvent::Call, Source::Push);
+        bed.on_event(105.0, 3, CallEvent::Call, Source::Push);
+        bed.on_event(130.0, 5, CallEvent::Assist, Source::Push);
+        bed.on_event(160.0, 6, CallEvent::Clear, Source::Command);
+        // a clear without a call is ignored
+        bed.on_event(170.0, 6, CallEvent::Clear, Source::Push);
+        bed.on_event(200.0, 0, CallEvent::Tamper, Source::Push);
+        bed.on_event(300.0, 2, CallEvent::Call, Source::Push);
+        bed.on_event(310.0, 6, CallEvent::Clear, Source::Push);
+        bed.on_event(400.0, 2, CallEvent::Call, Source::Push);
+
+        assert_eq!(bed.events.len(), 9);
+        assert_eq!((bed.calls.len(), bed.tampers), (3, 1));
+        assert_eq!(bed.calls[0].assist, Some(30.0));
+        assert_eq!(
+            bed.assist_stats(),
+            ResponseStats {
+                count: 1,
+                mean: Some(30.0),
+                max: Some(30.0)
+            }
+        );
+        let clear = bed.clear_stats();
+        assert_eq!(
+            (clear.count, clear.mean, clear.max),
+            (2, Some(35.0), Some(60.0))
+        );
+        assert!(bed.open_call().is_some());
+    }
+}
@@ -0,0 +1,353 @@
+//! remote device configuration
+//!
+//! the config is an opaque block of bytes on the device, the config file names its fields:
+//!
+//! ```toml
+//! size = 12
+//! [[field]]
+//! name = "netstat_period"
+//! offset = 0
+//! size = 2        # 1 - 4 bytes, big endian
+//! value = 60
+//! ```
+//!
+//! messages, the target mac is last as in the push trailer:
+//! - write: RES_CONFIG, config bytes, mac; the device answers NOTIFY CONFIG_CHANGED, mac
+//! - read: REQ_CONFIG, mac; the device answers RES_CONFIG, config bytes, mac
+
+use std::{
+    error::Error,
+    fmt::Display,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    link::Link,
+    registry::{self, DeviceInfo, Mac},
+    retry::{RetryArgs, RetryPolicy},
+    test_esp::{MSG_TYPE_NOTIFY, MSG_TYPE_REQ_CONFIG, MSG_TYPE_RES_CONFIG, NOTIFY_CONFIG_CHANGED},
+    ConnectArgs,
+};
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    /// expected config (TOML)
+    #[arg(short, long)]
+    file: PathBuf,
+    /// device name from the registry, repeat for more devices
+    #[arg(short, long = "device", required_unless_present = "group")]
+    devices: Vec<String>,
+    /// every device of this registry group, `all` for every device but the coordinators
+    #[arg(short, long)]
+    group: Option<String>,
+    /// only read the config back and diff it
+    #[arg(long)]
+    read_only: bool,
+    /// seconds to wait for CONFIG_CHANGED and for the read back
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    #[clap(flatten)]
+    retry_args: RetryArgs,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    size: usize,
+    #[serde(default)]
+    field: Vec<FieldEntry>,
+}
+
+#[derive(Deserialize)]
+struct FieldEntry {
+    name: String,
+    offset: usize,
+    #[serde(default = "default_field_size")]
+    size: usize,
+    value: u32,
+}
+
+fn default_field_size() -> usize {
+    1
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    offset: usize,
+    size: usize,
+}
+
+impl Field {
+    fn value(&self, bytes: &[u8]) -> Option<u32> {
+        let raw = bytes.get(self.offset..(self.offset + self.size))?;
+        Some(raw.iter().fold(0, |v, b| (v << 8) | *b as u32))
+    }
+}
+
+/// the config file as named fields and the bytes they encode to
+#[derive(Debug)]
+pub(crate) struct ExpectedConfig {
+    fields: Vec<Field>,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct FieldDiff {
+    pub name: String,
+    pub expected: u32,
+    // None when the read back is too short
+    pub actual: Option<u32>,
+}
+
+impl Display for FieldDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.actual {
+            Some(actual) => write!(
+                f,
+                "{}: expected {} got {}",
+                self.name, self.expected, actual
+            ),
+            None => write!(f, "{}: expected {} got nothing", self.name, self.expected),
+        }
+    }
+}
+
+impl ExpectedConfig {
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let file: ConfigFile = toml::from_str(text)?;
+        let mut bytes = vec![0; file.size];
+        let mut used = vec![false; file.size];
+        let mut fields = Vec::new();
+        for entry in file.field {
+            if !(1..=4).contains(&entry.size) {
+                return Err(format!("{}: size {} not in 1 - 4", entry.name, entry.size).into());
+            }
+            let end = entry.offset + entry.size;
+            if end > file.size {
+                return Err(
+                    format!("{}: ends at {end} past size {}", entry.name, file.size).into(),
+                );
+            }
+            if entry.size < 4 && entry.value >> (8 * entry.size) != 0 {
+                return Err(format!("{}: {} does not fit", entry.name, entry.value).into());
+            }
+            if used[entry.offset..end].iter().any(|u| *u) {
+                return Err(format!("{}: overlaps another field", entry.name).into());
+            }
+            used[entry.offset..end].fill(true);
+            bytes[entry.offset..end]
+                .copy_from_slice(&entry.value.to_be_bytes()[(4 - entry.size)..]);
+            fields.push(Field {
+                name: entry.name,
+                offset: entry.offset,
+                size: entry.size,
+            });
+        }
+        // bytes not named in the file are expected to stay 0
+        for (offset, _) in used.iter().enumerate().filter(|(_, u)| !**u) {
+            fields.push(Field {
+                name: format!("byte[{offset}]"),
+                offset,
+                size: 1,
+            });
+        }
+        fields.sort_by_key(|f| f.offset);
+        Ok(Self { fields, bytes })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// fields of `actual` that differ from the expected config
+    pub fn diff(&self, actual: &[u8]) -> Vec<FieldDiff> {
+        self.fields
+            .iter()
+            .filter_map(|field| {
+                let expected = field.value(&self.bytes).unwrap();
+                let actual = field.value(actual);
+                (actual != Some(expected)).then(|| FieldDiff {
+                    name: field.name.clone(),
+                    expected,
+                    actual,
+                })
+            })
+            .collect()
+    }
+}
+
+/// outcome per device, the steps are "ok", "-" when skipped or the error
+struct DeviceResult {
+    name: String,
+    write: String,
+    changed: String,
+    read: String,
+    diffs: Vec<FieldDiff>,
+}
+
+impl DeviceResult {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            write: "-".into(),
+            changed: "-".into(),
+            read: "-".into(),
+            diffs: Vec::new(),
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        [&self.write, &self.changed]
+            .iter()
+            .all(|step| *step == "ok" || *step == "-")
+            && self.read == "ok"
+            && self.diffs.is_empty()
+    }
+}
+
+fn write_config(link: &mut Link, mac: &Mac, config: &[u8], timeout: Duration) -> (String, String) {
+    let mut payload = vec![MSG_TYPE_RES_CONFIG, 0];
+    payload.extend_from_slice(config);
+    payload.extend_from_slice(mac);
+    if let Err(e) = link.send(&payload) {
+        return (e, "-".into());
+    }
+    let changed = link.recv(Instant::now() + timeout, |f| {
+        f.msg_type == MSG_TYPE_NOTIFY
+            && f.data.first() == Some(&NOTIFY_CONFIG_CHANGED)
+            && f.data.get(1..7) == Some(&mac[..])
+    });
+    match changed {
+        Some(_) => ("ok".into(), "ok".into()),
+        None => ("ok".into(), "timeout".into()),
+    }
+}
+
+fn read_config(link: &mut Link, mac: &Mac, timeout: Duration) -> Result<Vec<u8>, String> {
+    let mut payload = vec![MSG_TYPE_REQ_CONFIG, 0];
+    payload.extend_from_slice(mac);
+    link.send(&payload)?;
+    let frame = link
+        .recv(Instant::now() + timeout, |f| {
+            f.msg_type == MSG_TYPE_RES_CONFIG && f.data.ends_with(mac)
+        })
+        .ok_or("timeout")?;
+    Ok(frame.data[..(frame.data.len() - mac.len())].to_vec())
+}
+
+fn apply(
+    link: &mut Link,
+    device: &DeviceInfo,
+    expected: &ExpectedConfig,
+    args: &ConfigArgs,
+) -> DeviceResult {
+    let timeout = Duration::from_secs(args.timeout);
+    let mut result = DeviceResult::new(&device.name);
+    if !args.read_only {
+        (result.write, result.changed) = write_config(link, &device.mac, expected.bytes(), timeout);
+        info!(
+            "CONFIG: {} write:{} changed:{}",
+            device.name, result.write, result.changed
+        );
+    }
+    match read_config(link, &device.mac, timeout) {
+        Ok(actual) => {
+            result.read = "ok".into();
+            result.diffs = expected.diff(&actual);
+        }
+        Err(e) => result.read = e,
+    }
+    result
+}
+
+/// write the config to the selected devices, read it back and print the differences
+pub fn config(args: ConfigArgs) -> Result<(), Box<dyn Error>> {
+    let expected = ExpectedConfig::parse(&std::fs::read_to_string(&args.file)?)?;
+    let targets = registry::targets(&args.devices, args.group.as_deref())?;
+
+    let policy = RetryPolicy::new(args.retry_args.clone());
+    let mut link = Link::open(&args.connect_args, policy)?;
+    let mut results = Vec::new();
+    for target in targets {
+        match target {
+            Ok(device) => results.push(apply(&mut link, &device, &expected, &args)),
+            Err(name) => {
+                warn!("CONFIG: {name} is not in the registry");
+                let mut result = DeviceResult::new(&name);
+                result.read = "unknown".into();
+                results.push(result);
+            }
+        }
+    }
+
+    println!(
+        "{:>14} {:>10} {:>10} {:>10} {:>6}",
+        "DEVICE", "WRITE", "CHANGED", "READ", "DIFFS"
+    );
+    for r in &results {
+        println!(
+            "{:>14} {:>10} {:>10} {:>10} {:>6}",
+            r.name,
+            r.write,
+            r.changed,
+            r.read,
+            r.diffs.len()
+        );
+    }
+    for r in &results {
+        for diff in &r.diffs {
+            println!("{:>14} {diff}", r.name);
+        }
+    }
+    let failed = results.iter().filter(|r| !r.is_ok()).count();
+    if failed > 0 {
+        return Err(format!("{failed} of {} devices not configured", results.len()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_diff() {
+        let text = r#"
+            size = 6
+            [[field]]
+            name = "period"
+            offset = 0
+            size = 2
+            value = 300
+            [[field]]
+            name = "channel"
+            offset = 3
+            value = 6
+        "#;
+        let expected = ExpectedConfig::parse(text).unwrap();
+        assert_eq!(expected.bytes(), [0x01, 0x2c, 0, 6, 0, 0]);
+        assert!(expected.diff(&[0x01, 0x2c, 0, 6, 0, 0]).is_empty());
+
+        let diffs = expected.diff(&[0x00, 0x3c, 0, 6, 9]);
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(diffs[0].to_string(), "period: expected 300 got 60");
+        assert_eq!(diffs[1].to_string(), "byte[4]: expected 0 got 9");
+        assert_eq!(diffs[2].to_string(), "byte[5]: expected 0 got nothing");
+
+        assert!(ExpectedConfig::parse(
+            "size = 2\n[[field]]\nname = \"x\"\noffset = 1\nsize = 2\nvalue = 1"
+        )
+        .is_err());
+        assert!(ExpectedConfig::parse(
+            "size = 2\n[[field]]\nname = \"x\"\noffset = 0\nvalue = 256"
+        )
+        .is_err());
+    }
+}
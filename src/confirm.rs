@@ -0,0 +1,270 @@
+//! command-to-effect verification: a pin command sent by the tester is confirmed by the next
+//! PUSH_GPIO of its device reporting the commanded pin state
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::info;
+
+use crate::{
+    latency::Histogram,
+    registry::Mac,
+    test_esp::{MSG_TYPE_NOTIFY, NOTIFY_PIN_LED},
+};
+
+// type, part, notify type, mac, pin, mode, arg
+const PIN_COMMAND_SIZE: usize = 12;
+const MODE_OFF: u8 = 0;
+const MODE_ON: u8 = 1;
+const MODE_PULSE: u8 = 2;
+
+#[derive(Args, Clone, Copy, Debug)]
+pub struct ConfirmArgs {
+    /// milliseconds a GPIO command waits for the PUSH_GPIO confirming it
+    #[arg(long, default_value_t = 5000)]
+    pub confirm_timeout: u64,
+}
+
+impl Default for ConfirmArgs {
+    fn default() -> Self {
+        Self {
+            confirm_timeout: 5000,
+        }
+    }
+}
+
+/// NOTIFY PIN_LED as sent with `--send`, e.g. `7E0002{HWID}020205` pulses pin 2 for 500 ms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PinCommand {
+    pub mac: Mac,
+    pub pin: u8,
+    pub mode: u8,
+    // pulse length in 100 ms
+    pub arg: u8,
+}
+
+impl PinCommand {
+    /// None when `payload` is not a pin command
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() < PIN_COMMAND_SIZE
+            || payload[0] != MSG_TYPE_NOTIFY
+            || payload[2] != NOTIFY_PIN_LED
+        {
+            return None;
+        }
+        Some(Self {
+            mac: payload[3..9].try_into().unwrap(),
+            pin: payload[9],
+            mode: payload[10],
+            arg: payload[11],
+        })
+    }
+
+    /// on and pulse switch the pin active
+    pub fn is_active(&self) -> bool {
+        self.mode != MODE_OFF
+    }
+
+    /// a pulse has often ended before its push arrives, only an active report settles it
+    fn settled_by(&self, level: u8) -> bool {
+        self.mode != MODE_PULSE || level != 0
+    }
+}
+
+impl Display for PinCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.mode {
+            MODE_OFF => write!(f, "pin {} off", self.pin),
+            MODE_ON => write!(f, "pin {} on", self.pin),
+            MODE_PULSE => write!(f, "pin {} pulse {}ms", self.pin, self.arg as u32 * 100),
+            mode => write!(f, "pin {} mode {mode}", self.pin),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Confirmed(PinCommand, Duration),
+    /// the push reported the pin in the other state
+    Mismatch(PinCommand, u8, Duration),
+    NotConfirmed(PinCommand),
+}
+
+/// pin commands waiting for the PUSH_GPIO of their device
+#[derive(Debug, Default)]
+pub(crate) struct CommandVerifier {
+    args: ConfirmArgs,
+    // in sent order
+    pending: Vec<(Instant, PinCommand)>,
+    latency: Histogram,
+    confirmed: u32,
+    mismatched: u32,
+    unconfirmed: u32,
+}
+
+impl CommandVerifier {
+    pub fn new(args: ConfirmArgs) -> Self {
+        Self {
+            args,
+            ..Default::default()
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.args.confirm_timeout)
+    }
+
+    pub fn on_command(&mut self, command: PinCommand, now: Instant) {
+        self.pending.push((now, command));
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// commands of `mac` settled by its PUSH_GPIO (`pin, level` pairs), the oldest per pin;
+    /// a pulse waits for a report of the pin active until it times out
+    pub fn on_gpio(&mut self, mac: &Mac, data: &[u8], now: Instant) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        for pair in data.chunks_exact(2) {
+            let (pin, level) = (pair[0], pair[1]);
+            let Some(pos) = self
+                .pending
+                .iter()
+                .position(|(_, cmd)| cmd.mac == *mac && cmd.pin == pin && cmd.settled_by(level))
+            else {
+                continue;
+            };
+            let (sent_at, command) = self.pending.remove(pos);
+            let latency = now - sent_at;
+            if command.is_active() == (level != 0) {
+                self.confirmed += 1;
+                self.latency.record(latency);
+                outcomes.push(Outcome::Confirmed(command, latency));
+            } else {
+                self.mismatched += 1;
+                outcomes.push(Outcome::Mismatch(command, level, latency));
+            }
+        }
+        outcomes
+    }
+
+    /// commands still not confirmed after the timeout
+    pub fn expire(&mut self, now: Instant) -> Vec<Outcome> {
+        let timeout = self.timeout();
+        let (expired, pending) = self
+            .pending
+            .drain(..)
+            .partition(|(sent_at, _)| now - *sent_at >= timeout);
+        self.pending = pending;
+        let expired: Vec<Outcome> = expired
+            .into_iter()
+            .map(|(_, cmd)| Outcome::NotConfirmed(cmd))
+            .collect();
+        self.unconfirmed += expired.len() as u32;
+        expired
+    }
+
+    pub fn log_report(&self) {
+        if self.confirmed + self.mismatched + self.unconfirmed == 0 && self.pending.is_empty() {
+            return;
+        }
+        info!(
+            "GPIO CONFIRM: confirmed:{} mismatched:{} not confirmed:{} pending:{} latency {}",
+            self.confirmed,
+            self.mismatched,
+            self.unconfirmed,
+            self.pending.len(),
+            self.latency
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_confirm() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mac = [0x68, 0x67, 0x25, 0x4e, 0xed, 0x84];
+        let parse = |hex: &str| PinCommand::parse(&hex::decode(hex).unwrap()).unwrap();
+        let pulse = parse("7e00026867254eed84020205");
+        assert_eq!(pulse.to_string(), "pin 2 pulse 500ms");
+        assert_eq!(
+            PinCommand::parse(&hex::decode("7e00046867254eed84").unwrap()),
+            None
+        );
+
+        let mut verifier = CommandVerifier::new(ConfirmArgs::default());
+        verifier.on_command(pulse, t0);
+        verifier.on_command(parse("7e00026867254eed84000100"), t0);
+        verifier.on_command(parse("7e00026867254eed84000000"), t0 + ms(100));
+        verifier.on_command(parse("7e00026867254eed84030100"), t0);
+
+        // pin 0 on confirms the first pin 0 command only, pin 2 off leaves the pulse pending
+        let outcomes = verifier.on_gpio(&mac, &[0, 1, 2, 0], t0 + ms(300));
+        assert_eq!(
+            outcomes,
+            [Outcome::Confirmed(
+                parse("7e00026867254eed84000100"),
+                ms(300)
+            )]
+        );
+        assert_eq!(
+            verifier.on_gpio(&mac, &[3, 0], t0 + ms(350)),
+            [Outcome::Mismatch(
+                parse("7e00026867254eed84030100"),
+                0,
+                ms(350)
+            )]
+        );
+        assert!(verifier.on_gpio(&[0; 6], &[0, 0], t0 + ms(400)).is_empty());
+        assert_eq!(
+            verifier.on_gpio(&mac, &[0, 0], t0 + ms(600)),
+            [Outcome::Confirmed(
+                parse("7e00026867254eed84000000"),
+                ms(500)
+            )]
+        );
+
+        assert!(verifier.expire(t0 + ms(4999)).is_empty());
+        assert_eq!(
+            verifier.expire(t0 + ms(5000)),
+            [Outcome::NotConfirmed(pulse)]
+        );
+        assert_eq!(
+            (
+                verifier.confirmed,
+                verifier.mismatched,
+                verifier.unconfirmed
+            ),
+            (2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_confirm_pulse() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mac = [0x68, 0x67, 0x25, 0x4e, 0xed, 0x84];
+        let pulse = PinCommand::parse(&hex::decode("7e00026867254eed84020205").unwrap()).unwrap();
+        let mut verifier = CommandVerifier::new(ConfirmArgs::default());
+        verifier.on_command(pulse, t0);
+
+        // the 500 ms pulse is over by the time this push arrives
+        assert!(verifier.on_gpio(&mac, &[2, 0], t0 + ms(800)).is_empty());
+        assert!(verifier.has_pending());
+        // a later push saw it active
+        assert_eq!(
+            verifier.on_gpio(&mac, &[2, 1], t0 + ms(1200)),
+            [Outcome::Confirmed(pulse, ms(1200))]
+        );
+        assert!(!verifier.has_pending());
+        assert_eq!(verifier.mismatched, 0);
+    }
+}
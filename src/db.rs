@@ -0,0 +1,642 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Args, ValueEnum};
+use log::{error, info};
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::{registry::Mac, series::NetstatSample};
+
+// rows inserted in one transaction at most
+const BATCH_SIZE: usize = 500;
+// how long the writer collects rows before it commits
+const BATCH_PERIOD: Duration = Duration::from_secs(1);
+// how long closing waits for the queued rows
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started REAL NOT NULL,
+    ended REAL,
+    port TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS devices (
+    run_id INTEGER NOT NULL,
+    mac TEXT NOT NULL,
+    name TEXT NOT NULL,
+    role TEXT NOT NULL,
+    first_seen REAL NOT NULL,
+    PRIMARY KEY (run_id, mac)
+);
+CREATE TABLE IF NOT EXISTS netstat (
+    run_id INTEGER NOT NULL,
+    ts REAL NOT NULL,
+    mac TEXT NOT NULL,
+    rssi INTEGER, snr INTEGER, resent INTEGER, failed_queued INTEGER, failed INTEGER,
+    sent INTEGER, rx_ntfy INTEGER, rx_bcast INTEGER, rx_direct INTEGER,
+    relay_req INTEGER, relay_ntfy INTEGER,
+    net_stat_ts INTEGER, push_id INTEGER, next_node TEXT
+);
+CREATE TABLE IF NOT EXISTS gpio (
+    run_id INTEGER NOT NULL,
+    ts REAL NOT NULL,
+    mac TEXT NOT NULL,
+    push_id INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS route_changes (
+    run_id INTEGER NOT NULL,
+    ts REAL NOT NULL,
+    mac TEXT NOT NULL,
+    from_mac TEXT NOT NULL,
+    to_mac TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS frames (
+    run_id INTEGER NOT NULL,
+    ts REAL NOT NULL,
+    seq INTEGER NOT NULL,
+    payload_len INTEGER NOT NULL,
+    retries INTEGER NOT NULL,
+    acked INTEGER NOT NULL,
+    rtt_ms REAL
+);
+CREATE TABLE IF NOT EXISTS errors (
+    run_id INTEGER NOT NULL,
+    ts REAL NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS netstat_run ON netstat (run_id, mac);
+";
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct DbArgs {
+    /// record devices, samples, frames and errors of this run in a SQLite database
+    #[arg(long)]
+    pub db: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportKind {
+    /// one line per run
+    Runs,
+    /// a NETSTAT counter summed per device and run
+    Devices,
+    /// sent, acked and abandoned frames per run
+    Frames,
+    /// protocol errors per run
+    Errors,
+    /// next node changes per device and run
+    Routes,
+}
+
+// NETSTAT columns the device report can sum
+const DEVICE_COLUMNS: [&str; 11] = [
+    "failed",
+    "failed_queued",
+    "resent",
+    "sent",
+    "rx_ntfy",
+    "rx_bcast",
+    "rx_direct",
+    "relay_req",
+    "relay_ntfy",
+    "rssi",
+    "snr",
+];
+
+fn parse_column(s: &str) -> Result<String, String> {
+    let s = s.replace('-', "_");
+    if DEVICE_COLUMNS.contains(&s.as_str()) {
+        Ok(s)
+    } else {
+        Err(format!("`{s}` is not one of {}", DEVICE_COLUMNS.join(", ")))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReportArgs {
+    /// database written by `test --db`
+    #[arg(long)]
+    db: PathBuf,
+    #[arg(value_enum, default_value_t = ReportKind::Runs)]
+    kind: ReportKind,
+    /// look at the last N runs
+    #[arg(long, default_value_t = 7)]
+    runs: u32,
+    /// NETSTAT counter for the devices report (rssi and snr are averaged)
+    #[arg(long, default_value = "failed", value_parser = parse_column)]
+    column: String,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+pub(crate) struct Db {
+    conn: Connection,
+    run_id: i64,
+}
+
+/// one row for the writer thread, stamped when it was recorded
+#[derive(Debug)]
+enum Record {
+    Device {
+        ts: f64,
+        mac: Mac,
+        name: String,
+        role: String,
+    },
+    Netstat(NetstatSample),
+    Gpio {
+        ts: f64,
+        mac: Mac,
+        push_id: u16,
+        data: Vec<u8>,
+    },
+    RouteChange {
+        ts: f64,
+        mac: Mac,
+        from: Mac,
+        to: Mac,
+    },
+    Frame {
+        ts: f64,
+        seq_no: u16,
+        payload_len: usize,
+        retries: u32,
+        rtt: Option<Duration>,
+    },
+    Error {
+        ts: f64,
+        kind: &'static str,
+        detail: String,
+    },
+}
+
+impl Db {
+    pub fn open(path: &Path, port: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // commits only sync at checkpoints, the writer thread batches them
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute(
+            "INSERT INTO runs (started, port) VALUES (?1, ?2)",
+            params![now(), port],
+        )?;
+        let run_id = conn.last_insert_rowid();
+        Ok(Self { conn, run_id })
+    }
+
+    fn insert(&self, record: &Record) -> rusqlite::Result<usize> {
+        let run_id = self.run_id;
+        match record {
+            Record::Device {
+                ts,
+                mac,
+                name,
+                role,
+            } => self.conn.execute(
+                "INSERT OR IGNORE INTO devices (run_id, mac, name, role, first_seen) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![run_id, hex::encode(mac), name, role, ts],
+            ),
+            Record::Netstat(s) => self.conn.execute(
+                "INSERT INTO netstat VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+                params![
+                    run_id,
+                    s.ts,
+                    s.mac,
+                    s.rssi,
+                    s.snr,
+                    s.resent,
+                    s.failed_queued,
+                    s.failed,
+                    s.sent,
+                    s.rx_ntfy,
+                    s.rx_bcast,
+                    s.rx_direct,
+                    s.relay_req,
+                    s.relay_ntfy,
+                    s.net_stat_ts,
+                    s.push_id,
+                    s.next_node
+                ],
+            ),
+            Record::Gpio {
+                ts,
+                mac,
+                push_id,
+                data,
+            } => self.conn.execute(
+                "INSERT INTO gpio VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![run_id, ts, hex::encode(mac), push_id, hex::encode(data)],
+            ),
+            Record::RouteChange { ts, mac, from, to } => self.conn.execute(
+                "INSERT INTO route_changes VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    run_id,
+                    ts,
+                    hex::encode(mac),
+                    hex::encode(from),
+                    hex::encode(to)
+                ],
+            ),
+            Record::Frame {
+                ts,
+                seq_no,
+                payload_len,
+                retries,
+                rtt,
+            } => self.conn.execute(
+                "INSERT INTO frames VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    run_id,
+                    ts,
+                    seq_no,
+                    payload_len,
+                    retries,
+                    rtt.is_some(),
+                    rtt.map(|rtt| rtt.as_secs_f64() * 1000.0)
+                ],
+            ),
+            Record::Error { ts, kind, detail } => self.conn.execute(
+                "INSERT INTO errors VALUES (?1, ?2, ?3, ?4)",
+                params![run_id, ts, kind, detail],
+            ),
+        }
+    }
+
+    /// all records in one transaction
+    fn insert_batch(&self, records: &[Record]) -> rusqlite::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for record in records {
+            if let Err(e) = self.insert(record) {
+                error!("DB: {e}");
+            }
+        }
+        tx.commit()
+    }
+
+    /// mark the run as ended
+    fn end_run(&self) -> rusqlite::Result<usize> {
+        self.conn.execute(
+            "UPDATE runs SET ended = ?1 WHERE id = ?2",
+            params![now(), self.run_id],
+        )
+    }
+}
+
+enum Msg {
+    Record(Record),
+    // write what is queued, end the run and answer
+    Close(Sender<()>),
+}
+
+/// records rows from any thread, a writer thread inserts them in batches; the default writer
+/// records nothing
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DbWriter {
+    tx: Option<Sender<Msg>>,
+}
+
+impl DbWriter {
+    /// start a new run in `path`, everything recorded afterwards is tagged with it
+    pub fn open(path: &Path, port: &str) -> rusqlite::Result<Self> {
+        let db = Db::open(path, port)?;
+        info!("DB: run {} in {}", db.run_id, path.display());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || write_loop(db, rx));
+        Ok(Self { tx: Some(tx) })
+    }
+
+    fn record(&self, record: Record) {
+        if let Some(tx) = &self.tx {
+            // the writer thread only stops when the run is closed
+            let _ = tx.send(Msg::Record(record));
+        }
+    }
+
+    /// write what is queued and mark the run as ended
+    pub fn close(&self) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let (done, wait) = mpsc::channel();
+        if tx.send(Msg::Close(done)).is_ok() {
+            let _ = wait.recv_timeout(CLOSE_TIMEOUT);
+        }
+    }
+
+    pub fn device(&self, mac: &Mac, name: &str, role: &str) {
+        self.record(Record::Device {
+            ts: now(),
+            mac: *mac,
+            name: name.to_string(),
+            role: role.to_string(),
+        });
+    }
+
+    pub fn netstat(&self, sample: &NetstatSample) {
+        self.record(Record::Netstat(sample.clone()));
+    }
+
+    pub fn gpio(&self, mac: &Mac, push_id: u16, data: &[u8]) {
+        self.record(Record::Gpio {
+            ts: now(),
+            mac: *mac,
+            push_id,
+            data: data.to_vec(),
+        });
+    }
+
+    pub fn route_change(&self, mac: &Mac, from: &Mac, to: &Mac) {
+        self.record(Record::RouteChange {
+            ts: now(),
+            mac: *mac,
+            from: *from,
+            to: *to,
+        });
+    }
+
+    /// a frame that was ACKed (with its RTT) or abandoned
+    pub fn frame(&self, seq_no: u16, payload_len: usize, retries: u32, rtt: Option<Duration>) {
+        self.record(Record::Frame {
+            ts: now(),
+            seq_no,
+            payload_len,
+            retries,
+            rtt,
+        });
+    }
+
+    pub fn protocol_error(&self, kind: &'static str, detail: &str) {
+        self.record(Record::Error {
+            ts: now(),
+            kind,
+            detail: detail.to_string(),
+        });
+    }
+}
+
+/// collect records for up to BATCH_PERIOD after the first one and insert them together
+fn write_loop(db: Db, rx: Receiver<Msg>) {
+    let mut batch = Vec::new();
+    while let Ok(first) = rx.recv() {
+        let mut close = None;
+        let deadline = Instant::now() + BATCH_PERIOD;
+        let mut next = Some(first);
+        while let Some(msg) = next.take() {
+            match msg {
+                Msg::Record(record) => batch.push(record),
+                Msg::Close(done) => {
+                    close = Some(done);
+                    break;
+                }
+            }
+            if batch.len() < BATCH_SIZE {
+                let left = deadline.saturating_duration_since(Instant::now());
+                next = rx.recv_timeout(left).ok();
+            }
+        }
+        if let Err(e) = db.insert_batch(&batch) {
+            error!("DB: {e}");
+        }
+        batch.clear();
+        if let Some(done) = close {
+            if let Err(e) = db.end_run() {
+                error!("DB: {e}");
+            }
+            drop(db);
+            let _ = done.send(());
+            return;
+        }
+    }
+}
+
+type Table = (Vec<String>, Vec<Vec<String>>);
+
+fn query(conn: &Connection, sql: &str, runs: u32) -> rusqlite::Result<Table> {
+    let mut stmt = conn.prepare(sql)?;
+    let header = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let columns = stmt.column_count();
+    let rows = stmt
+        .query_map([runs], |row| {
+            (0..columns)
+                .map(|i| {
+                    Ok(match row.get_ref(i)? {
+                        rusqlite::types::ValueRef::Null => "-".to_string(),
+                        rusqlite::types::ValueRef::Integer(v) => v.to_string(),
+                        rusqlite::types::ValueRef::Real(v) => format!("{v:.1}"),
+                        rusqlite::types::ValueRef::Text(v) => String::from_utf8_lossy(v).into(),
+                        rusqlite::types::ValueRef::Blob(v) => hex::encode(v),
+                    })
+                })
+                .collect()
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok((header, rows))
+}
+
+// the runs looked at, ?1 is their count
+const LAST_RUNS: &str = "SELECT id FROM runs ORDER BY id DESC LIMIT ?1";
+
+/// rows of (device, run, value) turned into one column per run
+fn pivot(conn: &Connection, sql: &str, runs: u32) -> rusqlite::Result<Table> {
+    let (_, rows) = query(conn, sql, runs)?;
+    let mut run_ids: Vec<String> = rows.iter().map(|r| r[1].clone()).collect();
+    run_ids.sort_by_key(|id| id.parse::<i64>().unwrap_or_default());
+    run_ids.dedup();
+    let mut devices: Vec<String> = rows.iter().map(|r| r[0].clone()).collect();
+    devices.sort();
+    devices.dedup();
+    let header = std::iter::once("device".to_string())
+        .chain(run_ids.iter().map(|id| format!("run {id}")))
+        .collect();
+    let table = devices
+        .iter()
+        .map(|device| {
+            std::iter::once(device.clone())
+                .chain(run_ids.iter().map(|id| {
+                    rows.iter()
+                        .find(|r| &r[0] == device && &r[1] == id)
+                        .map_or("-".to_string(), |r| r[2].clone())
+                }))
+                .collect()
+        })
+        .collect();
+    Ok((header, table))
+}
+
+fn report_table(conn: &Connection, args: &ReportArgs) -> rusqlite::Result<Table> {
+    match args.kind {
+        ReportKind::Runs => query(
+            conn,
+            &format!(
+                "SELECT r.id AS run, datetime(r.started, 'unixepoch', 'localtime') AS started,
+                    (COALESCE(r.ended, (SELECT MAX(ts) FROM netstat n WHERE n.run_id = r.id)) - r.started) / 3600.0 AS hours,
+                    r.port,
+                    (SELECT COUNT(*) FROM devices d WHERE d.run_id = r.id) AS devices,
+                    (SELECT COUNT(*) FROM netstat n WHERE n.run_id = r.id) AS netstats,
+                    (SELECT COUNT(*) FROM frames f WHERE f.run_id = r.id) AS frames,
+                    (SELECT COUNT(*) FROM errors e WHERE e.run_id = r.id) AS errors
+                FROM runs r WHERE r.id IN ({LAST_RUNS}) ORDER BY r.id"
+            ),
+            args.runs,
+        ),
+        ReportKind::Devices => {
+            // rssi/snr are levels, the rest are per interval deltas
+            let agg = if matches!(args.column.as_str(), "rssi" | "snr") {
+                "AVG"
+            } else {
+                "SUM"
+            };
+            pivot(
+                conn,
+                &format!(
+                    "SELECT COALESCE(d.name, n.mac), n.run_id, {agg}(n.{column})
+                    FROM netstat n LEFT JOIN devices d ON d.run_id = n.run_id AND d.mac = n.mac
+                    WHERE n.run_id IN ({LAST_RUNS}) GROUP BY n.mac, n.run_id",
+                    column = args.column
+                ),
+                args.runs,
+            )
+        }
+        ReportKind::Frames => query(
+            conn,
+            &format!(
+                "SELECT run_id AS run, COUNT(*) AS frames, SUM(acked) AS acked,
+                    COUNT(*) - SUM(acked) AS abandoned, AVG(retries) AS retries,
+                    AVG(rtt_ms) AS avg_rtt_ms, MAX(rtt_ms) AS max_rtt_ms
+                FROM frames WHERE run_id IN ({LAST_RUNS}) GROUP BY run_id ORDER BY run_id"
+            ),
+            args.runs,
+        ),
+        ReportKind::Errors => query(
+            conn,
+            &format!(
+                "SELECT run_id AS run, kind, COUNT(*) AS count FROM errors
+                WHERE run_id IN ({LAST_RUNS}) GROUP BY run_id, kind ORDER BY run_id, kind"
+            ),
+            args.runs,
+        ),
+        ReportKind::Routes => pivot(
+            conn,
+            &format!(
+                "SELECT COALESCE(d.name, r.mac), r.run_id, COUNT(*)
+                FROM route_changes r LEFT JOIN devices d ON d.run_id = r.run_id AND d.mac = r.mac
+                WHERE r.run_id IN ({LAST_RUNS}) GROUP BY r.mac, r.run_id"
+            ),
+            args.runs,
+        ),
+    }
+}
+
+/// a database recorded with `test --db`, read-only so a report never creates or changes one
+fn open_report(path: &Path) -> Result<Connection, Box<dyn Error>> {
+    if !path.exists() {
+        return Err(format!("{}: no such database", path.display()).into());
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let has_runs: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'runs')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_runs {
+        return Err(format!("{}: no runs recorded", path.display()).into());
+    }
+    Ok(conn)
+}
+
+pub(crate) fn report(args: ReportArgs) -> Result<(), Box<dyn Error>> {
+    let conn = open_report(&args.db)?;
+    let (header, rows) = report_table(&conn, &args)?;
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].len())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{c:>w$}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    println!("{}", line(&header));
+    for row in &rows {
+        println!("{}", line(row));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_failed_per_device() {
+        let path =
+            std::env::temp_dir().join(format!("serial-rs-tests-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // a report neither creates a database nor reads one without runs
+        assert!(open_report(&path).is_err());
+        assert!(!path.exists());
+        std::fs::write(&path, "").unwrap();
+        assert!(open_report(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        for failed in [1, 2] {
+            let db = Db::open(&path, "/dev/null").unwrap();
+            db.conn
+                .execute(
+                    "INSERT INTO netstat (run_id, ts, mac, failed) VALUES (?1, 0, 'aa', ?2), (?1, 1, 'aa', ?2)",
+                    params![db.run_id, failed],
+                )
+                .unwrap();
+        }
+        let conn = open_report(&path).unwrap();
+        let args = ReportArgs {
+            db: path.clone(),
+            kind: ReportKind::Devices,
+            runs: 7,
+            column: "failed".into(),
+        };
+        let (header, rows) = report_table(&conn, &args).unwrap();
+        assert_eq!(header, ["device", "run 1", "run 2"]);
+        assert_eq!(rows, [["aa", "2", "4"]]);
+        assert!(parse_column("failed-queued").is_ok());
+        assert!(parse_column("1; DROP TABLE runs").is_err());
+
+        // closing writes the queued rows and ends the run
+        let writer = DbWriter::open(&path, "/dev/null").unwrap();
+        writer.frame(1, 12, 0, Some(Duration::from_millis(20)));
+        writer.protocol_error("checksum", "00");
+        writer.close();
+        let written: (i64, i64, bool) = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM frames), (SELECT COUNT(*) FROM errors),
+                    (SELECT ended IS NOT NULL FROM runs WHERE id = 3)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(written, (1, 1, true));
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
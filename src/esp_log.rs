@@ -0,0 +1,288 @@
+//! ESP-IDF log lines in the debug-print frames (`00 00 7E` and text)
+//!
+//! `I (12345) wifi: connected`: level letter (E W I D V), milliseconds since boot, tag and
+//! message, the ANSI colors of the IDF console are stripped. Other text is logged as it is.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use clap::{Args, ValueEnum};
+use flexi_logger::DeferredNow;
+use log::{error, info, log, Level};
+
+use crate::registry::{self, Mac};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EspLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Verbose,
+}
+
+impl EspLevel {
+    fn level(self) -> Level {
+        match self {
+            EspLevel::Error => Level::Error,
+            EspLevel::Warn => Level::Warn,
+            EspLevel::Info => Level::Info,
+            EspLevel::Debug => Level::Debug,
+            EspLevel::Verbose => Level::Trace,
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct EspLogArgs {
+    /// only firmware log lines with these tags (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    pub esp_log_tag: Vec<String>,
+    /// firmware log lines less severe than this are dropped
+    #[arg(long, value_enum, default_value_t = EspLevel::Verbose)]
+    pub esp_log_level: EspLevel,
+    /// also write the firmware output to `<dir>/<device>.log`
+    #[arg(long)]
+    pub esp_log_dir: Option<PathBuf>,
+    /// device on the serial port (registry name or MAC) the firmware output is from, by
+    /// default the first coordinator sending a NETSTAT
+    #[arg(long)]
+    pub esp_log_device: Option<String>,
+}
+
+impl EspLogArgs {
+    /// the MAC of `--esp-log-device`
+    pub fn device(&self) -> Result<Option<Mac>, String> {
+        let Some(device) = &self.esp_log_device else {
+            return Ok(None);
+        };
+        match registry::find(device) {
+            Some(info) => Ok(Some(info.mac)),
+            None => registry::parse_mac(device)
+                .map(Some)
+                .map_err(|_| format!("`{device}` is neither a registry name nor a MAC")),
+        }
+    }
+}
+
+impl Default for EspLogArgs {
+    fn default() -> Self {
+        Self {
+            esp_log_tag: Vec::new(),
+            esp_log_level: EspLevel::Verbose,
+            esp_log_dir: None,
+            esp_log_device: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EspLogLine {
+    pub level: Level,
+    // the ESP-IDF letter, written back as is since `V` has no `log` level letter
+    pub letter: char,
+    // ms since boot, None for the `(hh:mm:ss.sss)` system time format
+    pub timestamp: Option<u64>,
+    pub tag: String,
+    pub message: String,
+}
+
+impl EspLogLine {
+    /// None when `text` is not an ESP-IDF log line
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = strip_ansi(text);
+        let text = text.trim_end_matches(['\r', '\n']);
+        let mut chars = text.chars();
+        let letter = chars.next()?;
+        let level = match letter {
+            'E' => Level::Error,
+            'W' => Level::Warn,
+            'I' => Level::Info,
+            'D' => Level::Debug,
+            'V' => Level::Trace,
+            _ => return None,
+        };
+        let rest = chars.as_str().strip_prefix(" (")?;
+        let (time, rest) = rest.split_once(") ")?;
+        let (tag, message) = rest.split_once(": ")?;
+        Some(Self {
+            level,
+            letter,
+            timestamp: time.parse().ok(),
+            tag: tag.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+// drop `ESC [ ... m` color sequences
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// filters the firmware output and spreads it over the device log files
+#[derive(Debug, Default)]
+pub(crate) struct EspLog {
+    args: EspLogArgs,
+    files: HashMap<String, File>,
+}
+
+impl EspLog {
+    pub fn new(args: EspLogArgs) -> Self {
+        Self {
+            args,
+            ..Default::default()
+        }
+    }
+
+    fn allows(&self, line: &EspLogLine) -> bool {
+        line.level <= self.args.esp_log_level.level()
+            && (self.args.esp_log_tag.is_empty() || self.args.esp_log_tag.contains(&line.tag))
+    }
+
+    fn write_file(&mut self, source: &str, text: &str) {
+        let Some(dir) = &self.args.esp_log_dir else {
+            return;
+        };
+        if !self.files.contains_key(source) {
+            let path = dir.join(format!("{}.log", source.replace([' ', '/'], "_")));
+            let opened = fs::create_dir_all(dir)
+                .and_then(|()| OpenOptions::new().create(true).append(true).open(&path));
+            match opened {
+                Ok(file) => {
+                    self.files.insert(source.to_string(), file);
+                }
+                Err(e) => {
+                    error!("ESP LOG: cannot open {}: {e}", path.display());
+                    self.args.esp_log_dir = None;
+                    return;
+                }
+            }
+        }
+        let file = self.files.get_mut(source).unwrap();
+        let now = DeferredNow::new()
+            .format("%Y-%m-%d %H:%M:%S%.6f")
+            .to_string();
+        let _ = writeln!(file, "{now} {text}");
+    }
+
+    /// the text of a debug-print frame from `source`, one log line per text line
+    pub fn on_debug(&mut self, source: &str, raw: &[u8]) {
+        let text = String::from_utf8_lossy(raw);
+        for line in text.split('\n').filter(|line| !line.trim().is_empty()) {
+            self.on_line(source, line);
+        }
+    }
+
+    fn on_line(&mut self, source: &str, text: &str) {
+        match EspLogLine::parse(text) {
+            Some(line) => {
+                if !self.allows(&line) {
+                    return;
+                }
+                let time = line.timestamp.map_or(String::new(), |t| format!(" ({t})"));
+                log!(
+                    line.level,
+                    "{source:>14}>ESP {}{time}: {}",
+                    line.tag,
+                    line.message
+                );
+                self.write_file(
+                    source,
+                    &format!("{}{time} {}: {}", line.letter, line.tag, line.message),
+                );
+            }
+            None => {
+                let text = text
+                    .trim_end_matches('\r')
+                    .as_bytes()
+                    .escape_ascii()
+                    .to_string();
+                info!("{source:>14}>ESP {text}");
+                self.write_file(source, &text);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_filter() {
+        let line =
+            EspLogLine::parse("\x1b[0;32mI (12345) wifi: connected: ch 6\x1b[0m\r\n").unwrap();
+        assert_eq!(
+            line,
+            EspLogLine {
+                level: Level::Info,
+                letter: 'I',
+                timestamp: Some(12345),
+                tag: "wifi".into(),
+                message: "connected: ch 6".into(),
+            }
+        );
+        let line = EspLogLine::parse("V (10:01:02.003) mesh: tick").unwrap();
+        assert_eq!((line.level, line.timestamp), (Level::Trace, None));
+        assert_eq!(EspLogLine::parse("boot: ESP-IDF v5.1"), None);
+        assert_eq!(EspLogLine::parse("I (12) no tag"), None);
+
+        let log = EspLog::new(EspLogArgs {
+            esp_log_tag: vec!["mesh".into()],
+            esp_log_level: EspLevel::Info,
+            esp_log_dir: None,
+            esp_log_device: None,
+        });
+        let parse = |text| EspLogLine::parse(text).unwrap();
+        assert!(log.allows(&parse("W (1) mesh: parent lost")));
+        assert!(!log.allows(&parse("D (1) mesh: tick")));
+        assert!(!log.allows(&parse("E (1) wifi: disconnected")));
+    }
+
+    #[test]
+    fn test_lines_and_device() {
+        let dir = std::env::temp_dir().join(format!("esp-log-{}", std::process::id()));
+        let mut log = EspLog::new(EspLogArgs {
+            esp_log_tag: vec!["mesh".into()],
+            esp_log_dir: Some(dir.clone()),
+            ..Default::default()
+        });
+        // the wifi line is filtered on its own tag, not kept in the mesh message
+        log.on_debug(
+            "Bed 103",
+            b"I (1) mesh: parent found\r\nW (2) wifi: beacon lost\r\nI (3) mesh: layer 2\r\nV (4) mesh: tick\r\n",
+        );
+        let written = fs::read_to_string(dir.join("Bed_103.log")).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("I (1) mesh: parent found"));
+        assert!(lines[1].ends_with("I (3) mesh: layer 2"));
+        assert!(lines[2].ends_with("V (4) mesh: tick"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let args = |device: &str| EspLogArgs {
+            esp_log_device: Some(device.into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            args("68:67:25:4d:62:58").device(),
+            Ok(Some([0x68, 0x67, 0x25, 0x4d, 0x62, 0x58]))
+        );
+        assert!(args("Bed 999").device().is_err());
+        assert_eq!(EspLogArgs::default().device(), Ok(None));
+    }
+}
@@ -0,0 +1,247 @@
+//! one command to a group of devices
+//!
+//! the hex command has `{mac}` where the target mac goes, e.g. `7E0002{mac}020205`. It is sent
+//! as one frame per device, or as a single frame to the broadcast mac when the firmware relays
+//! it to every device. Pin commands are confirmed by the PUSH_GPIO of each device.
+
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::{info, warn};
+
+use crate::{
+    confirm::{CommandVerifier, ConfirmArgs, Outcome, PinCommand},
+    link::Link,
+    registry::{self, DeviceInfo, Mac},
+    retry::{RetryArgs, RetryPolicy},
+    test_esp::gpio_push,
+    ConnectArgs,
+};
+
+const BROADCAST_MAC: Mac = [0xFF; 6];
+const MAC_PLACEHOLDER: &str = "{mac}";
+
+#[derive(Args)]
+pub struct SendArgs {
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    /// command as hex, `{mac}` stands for the mac of each device
+    #[arg(long)]
+    hex: String,
+    /// device name from the registry, repeat for more devices
+    #[arg(short, long = "device", required_unless_present = "group")]
+    devices: Vec<String>,
+    /// every device of this registry group, `all` for every device but the coordinators
+    #[arg(short, long)]
+    group: Option<String>,
+    /// send a single frame to the broadcast mac instead of one per device
+    #[arg(long)]
+    broadcast: bool,
+    #[clap(flatten)]
+    confirm_args: ConfirmArgs,
+    #[clap(flatten)]
+    retry_args: RetryArgs,
+}
+
+/// the command for `mac`, other `{device name}`s are expanded as in `--send`
+fn payload(hex: &str, mac: &Mac) -> Result<Vec<u8>, String> {
+    let hex = registry::expand_names(&hex.replace(MAC_PLACEHOLDER, &hex::encode(mac)))?;
+    hex::decode(&hex).map_err(|e| format!("bad hex `{hex}`: {e}"))
+}
+
+/// outcome per device, "-" for steps that do not apply
+struct Row {
+    name: String,
+    mac: Option<Mac>,
+    ack: String,
+    retries: Option<u32>,
+    confirm: String,
+    latency: Option<Duration>,
+}
+
+impl Row {
+    fn new(name: &str, mac: Option<Mac>) -> Self {
+        Self {
+            name: name.to_string(),
+            mac,
+            ack: "-".into(),
+            retries: None,
+            confirm: "-".into(),
+            latency: None,
+        }
+    }
+
+    fn on_send(&mut self, sent: &Result<u32, String>) {
+        match sent {
+            Ok(retries) => {
+                self.ack = "ok".into();
+                self.retries = Some(*retries);
+            }
+            Err(e) => self.ack = e.clone(),
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        self.ack == "ok" && (self.confirm == "ok" || self.confirm == "-")
+    }
+}
+
+fn on_outcome(rows: &mut [Row], outcome: Outcome) {
+    let (cmd, confirm, latency) = match outcome {
+        Outcome::Confirmed(cmd, latency) => (cmd, "ok".to_string(), Some(latency)),
+        Outcome::Mismatch(cmd, level, latency) => (cmd, format!("level {level}"), Some(latency)),
+        Outcome::NotConfirmed(cmd) => (cmd, "timeout".to_string(), None),
+    };
+    if let Some(row) = rows.iter_mut().find(|row| row.mac == Some(cmd.mac)) {
+        info!("SEND: {} {cmd} {confirm}", row.name);
+        row.confirm = confirm;
+        row.latency = latency;
+    }
+}
+
+/// send the command to every target and wait for the confirmations
+fn fan_out(
+    link: &mut Link,
+    args: &SendArgs,
+    devices: &[DeviceInfo],
+    rows: &mut [Row],
+) -> Result<(), Box<dyn Error>> {
+    let mut verifier = CommandVerifier::new(args.confirm_args);
+    let mut expect = |cmd: Option<PinCommand>, mac: &Mac, row: &mut Row| {
+        if let Some(cmd) = cmd {
+            verifier.on_command(PinCommand { mac: *mac, ..cmd }, Instant::now());
+            row.confirm = "pending".into();
+        }
+    };
+    if args.broadcast {
+        let payload = payload(&args.hex, &BROADCAST_MAC)?;
+        let sent = link.send(&payload);
+        let cmd = PinCommand::parse(&payload);
+        for (device, row) in devices.iter().zip(rows.iter_mut()) {
+            row.on_send(&sent);
+            if sent.is_ok() {
+                expect(cmd, &device.mac, row);
+            }
+        }
+    } else {
+        for (device, row) in devices.iter().zip(rows.iter_mut()) {
+            let payload = payload(&args.hex, &device.mac)?;
+            let sent = link.send(&payload);
+            row.on_send(&sent);
+            match &sent {
+                Ok(_) => expect(PinCommand::parse(&payload), &device.mac, row),
+                Err(e) => warn!("SEND: {} {e}", device.name),
+            }
+        }
+    }
+
+    // every command was sent by now
+    let deadline = Instant::now() + verifier.timeout();
+    while verifier.has_pending() {
+        let frame = link.recv(deadline, |f| gpio_push(f.msg_type, &f.data).is_some());
+        let Some(frame) = frame else {
+            break;
+        };
+        let (mac, gpio) = gpio_push(frame.msg_type, &frame.data).unwrap();
+        for outcome in verifier.on_gpio(&mac, gpio, Instant::now()) {
+            on_outcome(rows, outcome);
+        }
+    }
+    for outcome in verifier.expire(deadline) {
+        on_outcome(rows, outcome);
+    }
+    Ok(())
+}
+
+/// send one command to devices and groups and print the result per device
+pub fn send(args: SendArgs) -> Result<(), Box<dyn Error>> {
+    let mut devices = Vec::new();
+    let mut rows = Vec::new();
+    let mut unknown = Vec::new();
+    for target in registry::targets(&args.devices, args.group.as_deref())? {
+        match target {
+            Ok(device) => {
+                rows.push(Row::new(&device.name, Some(device.mac)));
+                devices.push(device);
+            }
+            Err(name) => {
+                warn!("SEND: {name} is not in the registry");
+                let mut row = Row::new(&name, None);
+                row.ack = "unknown".into();
+                unknown.push(row);
+            }
+        }
+    }
+
+    let policy = RetryPolicy::new(args.retry_args.clone());
+    let mut link = Link::open(&args.connect_args, policy)?;
+    fan_out(&mut link, &args, &devices, &mut rows)?;
+    rows.extend(unknown);
+
+    println!(
+        "{:>14} {:>10} {:>7} {:>9} {:>10}",
+        "DEVICE", "ACK", "RETRIES", "CONFIRM", "LATENCY"
+    );
+    for row in &rows {
+        println!(
+            "{:>14} {:>10} {:>7} {:>9} {:>10}",
+            row.name,
+            row.ack,
+            row.retries.map_or("-".into(), |r| r.to_string()),
+            row.confirm,
+            row.latency.map_or("-".into(), |l| format!("{l:.1?}")),
+        );
+    }
+    let acked = rows.iter().filter(|row| row.ack == "ok").count();
+    let confirmed = rows.iter().filter(|row| row.confirm == "ok").count();
+    let failed = rows.iter().filter(|row| !row.is_ok()).count();
+    println!(
+        "{} devices{}: {acked} ACKed, {confirmed} confirmed, {failed} failed",
+        rows.len(),
+        if args.broadcast { " (broadcast)" } else { "" }
+    );
+    if failed > 0 {
+        return Err(format!("{failed} of {} devices failed", rows.len()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload_and_outcomes() {
+        let mac = [0x68, 0x67, 0x25, 0x4e, 0xed, 0x84];
+        let bed = payload("7E0002{mac}020205", &mac).unwrap();
+        assert_eq!(hex::encode(&bed), "7e00026867254eed84020205");
+        let bcast = payload("7E0002{mac}060205", &BROADCAST_MAC).unwrap();
+        assert_eq!(PinCommand::parse(&bcast).unwrap().mac, BROADCAST_MAC);
+        assert!(payload("7E0002{mac}0", &mac).is_err());
+
+        let cmd = PinCommand::parse(&bed).unwrap();
+        let mut rows = [
+            Row::new("Bed 103", Some(mac)),
+            Row::new("Bed 105", Some([0; 6])),
+        ];
+        rows[0].on_send(&Ok(1));
+        rows[1].on_send(&Err("not ACKed".into()));
+        on_outcome(
+            &mut rows,
+            Outcome::Mismatch(cmd, 0, Duration::from_millis(80)),
+        );
+        assert_eq!(
+            (rows[0].retries, rows[0].confirm.as_str()),
+            (Some(1), "level 0")
+        );
+        on_outcome(
+            &mut rows,
+            Outcome::Confirmed(cmd, Duration::from_millis(90)),
+        );
+        assert!(rows[0].is_ok());
+        assert!(!rows[1].is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+};
+
+use clap::{Args, ValueEnum};
+use log::{info, warn};
+use rand::Rng;
+
+use crate::test_serial::{PushEscape, UartVec, AT_CMD, AT_ESC};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FaultKind {
+    /// flip one bit of the checksum
+    Checksum,
+    /// drop the trailing AT_CMD, the frame runs into the next one
+    /// (only meaningful without --at-cmd, which sends extra AT_CMDs)
+    DropEot,
+    /// insert a stray AT_CMD in the middle of the frame
+    StrayEot,
+    /// cut the frame short, keeping the original checksum
+    Truncate,
+    /// insert an escape sequence that is neither ESC ESC nor ESC EOT
+    BadEscape,
+    /// send the frame twice
+    Duplicate,
+}
+
+impl Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+fn parse_fault(s: &str) -> Result<(FaultKind, f64), String> {
+    let (kind, rate) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <fault>=<rate>, got `{s}`"))?;
+    let kind = FaultKind::from_str(kind, true)?;
+    let rate: f64 = rate
+        .parse()
+        .map_err(|e| format!("bad rate `{rate}`: {e}"))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("rate `{rate}` not in 0.0 - 1.0"));
+    }
+    Ok((kind, rate))
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct FaultArgs {
+    /// corrupt outgoing frames on purpose, e.g. `--fault checksum=0.05 --fault truncate=0.01`
+    /// (checksum, drop-eot, stray-eot, truncate, bad-escape, duplicate), rates add up to 1.0 at most
+    #[arg(long = "fault", value_parser = parse_fault)]
+    faults: Vec<(FaultKind, f64)>,
+}
+
+impl FaultArgs {
+    /// at most one fault is injected per frame, so the rates share one draw
+    fn check(&self) -> Result<(), String> {
+        let total: f64 = self.faults.iter().map(|(_, rate)| rate).sum();
+        if total > 1.0 {
+            return Err(format!("fault rates add up to {total}, more than 1.0"));
+        }
+        Ok(())
+    }
+}
+
+// resolved faults kept around to match late duplicate ACKs
+const RECENT_SIZE: usize = 64;
+
+// the framing has no explicit NACK: a device rejecting a frame stays silent,
+// so the retransmission that follows tells whether it recovered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    // ACKed without a resend, the corrupted frame was taken as valid
+    Accepted,
+    // corrupted frame ignored, a clean resend was ACKed
+    Recovered,
+    // ignored and never ACKed, abandoned after max retries
+    Lost,
+}
+
+#[derive(Default, Debug)]
+struct KindReport {
+    injected: u32,
+    accepted: u32,
+    recovered: u32,
+    lost: u32,
+    // ACKs for a faulted seq that was already resolved, e.g. a duplicate processed twice
+    dup_acked: u32,
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct FaultInjector {
+    rates: Vec<(FaultKind, f64)>,
+    // faulted seq waiting for the device reaction
+    pending: BTreeMap<u16, FaultKind>,
+    recent: VecDeque<(u16, FaultKind)>,
+    report: BTreeMap<FaultKind, KindReport>,
+}
+
+impl FaultInjector {
+    pub fn new(args: FaultArgs) -> Result<Self, String> {
+        args.check()?;
+        Ok(Self {
+            rates: args.faults,
+            ..Default::default()
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rates.is_empty()
+    }
+
+    // the kind whose slice of the cumulative rates holds `draw`, None past the last one
+    fn pick(&self, draw: f64) -> Option<FaultKind> {
+        let mut total = 0.0;
+        self.rates.iter().find_map(|(kind, rate)| {
+            total += rate;
+            (draw < total).then_some(*kind)
+        })
+    }
+
+    /// maybe corrupt a clean frame (`body` + escaped `csum` + AT_CMD); returns the bytes to write instead
+    pub fn inject(&mut self, seq_no: u16, body: &[u8], csum: u8) -> Option<UartVec> {
+        let mut rng = rand::thread_rng();
+        let kind = self.pick(rng.gen::<f64>())?;
+        let wire = corrupt(kind, body, csum, &mut rng);
+        if wire.len() < 50 {
+            warn!(
+                "FAULT SEQ:{:04X} {} injected {} bytes {}",
+                seq_no,
+                kind,
+                wire.len(),
+                hex::encode(&wire)
+            );
+        } else {
+            warn!(
+                "FAULT SEQ:{:04X} {} injected {} bytes {} ... {}",
+                seq_no,
+                kind,
+                wire.len(),
+                hex::encode(&wire[..25]),
+                hex::encode(&wire[(wire.len() - 25)..])
+            );
+        }
+        self.report.entry(kind).or_default().injected += 1;
+        if let Some(old_kind) = self.pending.insert(seq_no, kind) {
+            // seq wrapped before the earlier fault was resolved
+            self.report.entry(old_kind).or_default().lost += 1;
+        }
+        Some(wire)
+    }
+
+    fn resolve(&mut self, seq_no: u16, outcome: Outcome) {
+        let Some(kind) = self.pending.remove(&seq_no) else {
+            return;
+        };
+        info!("FAULT SEQ:{:04X} {} -> {:?}", seq_no, kind, outcome);
+        let report = self.report.entry(kind).or_default();
+        match outcome {
+            Outcome::Accepted => report.accepted += 1,
+            Outcome::Recovered => report.recovered += 1,
+            Outcome::Lost => report.lost += 1,
+        }
+        if self.recent.len() == RECENT_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((seq_no, kind));
+    }
+
+    pub fn on_ack(&mut self, seq_no: u16, retries: u32) {
+        if retries == 0 {
+            self.resolve(seq_no, Outcome::Accepted);
+        } else {
+            self.resolve(seq_no, Outcome::Recovered);
+        }
+    }
+
+    /// an ACK that matched no frame in flight
+    pub fn on_stray_ack(&mut self, seq_no: u16) {
+        if let Some(&(_, kind)) = self.recent.iter().rev().find(|(s, _)| *s == seq_no) {
+            warn!("FAULT SEQ:{:04X} {} ACKed again", seq_no, kind);
+            self.report.entry(kind).or_default().dup_acked += 1;
+        }
+    }
+
+    pub fn on_abandon(&mut self, seq_no: u16) {
+        self.resolve(seq_no, Outcome::Lost);
+    }
+
+    pub fn log_report(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        info!("FAULT REPORT: fault       injected accepted recovered lost dup-ack pending");
+        for (kind, r) in &self.report {
+            let pending = self.pending.values().filter(|&k| k == kind).count();
+            info!(
+                "FAULT REPORT: {:<10} {:>9} {:>8} {:>9} {:>4} {:>7} {:>7}",
+                kind, r.injected, r.accepted, r.recovered, r.lost, r.dup_acked, pending
+            );
+        }
+    }
+}
+
+// position inside the body after the seq header that does not split an escape pair
+fn split_point(body: &[u8], rng: &mut impl Rng) -> usize {
+    let min = 2.min(body.len());
+    let mut at = rng.gen_range(min..=body.len());
+    while at > min && body[at - 1] == AT_ESC {
+        at -= 1;
+    }
+    at
+}
+
+fn corrupt(kind: FaultKind, body: &[u8], csum: u8, rng: &mut impl Rng) -> UartVec {
+    let mut wire = UartVec::with_capacity(2 * body.len() + 8);
+    match kind {
+        FaultKind::Checksum => {
+            wire.extend_from_slice(body);
+            wire.push_escaped(csum ^ (1 << rng.gen_range(0..8)));
+            wire.push(AT_CMD);
+        }
+        FaultKind::DropEot => {
+            wire.extend_from_slice(body);
+            wire.push_escaped(csum);
+        }
+        FaultKind::StrayEot => {
+            let at = split_point(body, rng);
+            wire.extend_from_slice(&body[..at]);
+            wire.push(AT_CMD);
+            wire.extend_from_slice(&body[at..]);
+            wire.push_escaped(csum);
+            wire.push(AT_CMD);
+        }
+        FaultKind::Truncate => {
+            let at = split_point(&body[..body.len().saturating_sub(1)], rng);
+            wire.extend_from_slice(&body[..at]);
+            wire.push_escaped(csum);
+            wire.push(AT_CMD);
+        }
+        FaultKind::BadEscape => {
+            let at = split_point(body, rng);
+            wire.extend_from_slice(&body[..at]);
+            wire.push(AT_ESC);
+            wire.push(0x55);
+            wire.extend_from_slice(&body[at..]);
+            wire.push_escaped(csum);
+            wire.push(AT_CMD);
+        }
+        FaultKind::Duplicate => {
+            for _ in 0..2 {
+                wire.extend_from_slice(body);
+                wire.push_escaped(csum);
+                wire.push(AT_CMD);
+            }
+        }
+    }
+    wire
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_corrupt() {
+        let mut rng = rand::thread_rng();
+        let body = vec![0x01, 0x00, 0xFF, 0x10, 0x11, 0x12];
+        let csum = body.iter().fold(0u8, |c, b| c.wrapping_add(*b));
+
+        let wire = corrupt(FaultKind::Checksum, &body, csum, &mut rng);
+        assert_eq!(&wire[..body.len()], &body[..]);
+        assert_ne!(wire[body.len()], csum);
+        assert_eq!(*wire.last().unwrap(), AT_CMD);
+
+        let wire = corrupt(FaultKind::DropEot, &body, csum, &mut rng);
+        assert!(!wire.contains(&AT_CMD));
+
+        let wire = corrupt(FaultKind::StrayEot, &body, csum, &mut rng);
+        assert_eq!(wire.iter().filter(|&&b| b == AT_CMD).count(), 2);
+
+        let wire = corrupt(FaultKind::Truncate, &body, csum, &mut rng);
+        assert!(wire.len() < body.len() + 2);
+
+        let wire = corrupt(FaultKind::Duplicate, &body, csum, &mut rng);
+        assert_eq!(wire.len(), 2 * (body.len() + 2));
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let mut faults = FaultInjector::new(FaultArgs {
+            faults: vec![(FaultKind::Checksum, 1.0)],
+        })
+        .unwrap();
+        assert!(faults.inject(1, &[1, 0, 0xFF], 0).is_some());
+        assert!(faults.inject(2, &[2, 0, 0xFF], 1).is_some());
+        assert!(faults.inject(3, &[3, 0, 0xFF], 2).is_some());
+        faults.on_ack(1, 0);
+        faults.on_ack(2, 1);
+        faults.on_abandon(3);
+        faults.on_stray_ack(1);
+        faults.on_stray_ack(4);
+        let report = &faults.report[&FaultKind::Checksum];
+        assert_eq!(report.injected, 3);
+        assert_eq!(report.accepted, 1);
+        assert_eq!(report.recovered, 1);
+        assert_eq!(report.lost, 1);
+        assert_eq!(report.dup_acked, 1);
+        assert!(faults.pending.is_empty());
+        assert_eq!(parse_fault("stray-eot=0.5"), Ok((FaultKind::StrayEot, 0.5)));
+        assert!(parse_fault("truncate=2").is_err());
+
+        let faults = FaultInjector::new(FaultArgs {
+            faults: vec![(FaultKind::Checksum, 0.2), (FaultKind::Truncate, 0.3)],
+        })
+        .unwrap();
+        assert_eq!(faults.pick(0.1), Some(FaultKind::Checksum));
+        assert_eq!(faults.pick(0.4), Some(FaultKind::Truncate));
+        assert_eq!(faults.pick(0.5), None);
+        assert!(FaultInjector::new(FaultArgs {
+            faults: vec![(FaultKind::Checksum, 0.6), (FaultKind::Truncate, 0.6)],
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::{error::Error, io::Write, path::PathBuf};
+
+use clap::{Args, ValueEnum};
+
+use crate::test_serial::encode_frame;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// 0x00, 0x01, ... wrapping at 0xFF
+    Counter,
+    /// ASCII digits '1', '2', ... '9', '0', ...
+    Digits,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// hex string
+    Hex,
+    /// raw bytes on stdout
+    Bin,
+    /// C array
+    C,
+    /// Rust byte array
+    Rust,
+}
+
+fn parse_num<T: TryFrom<u32>>(s: &str) -> Result<T, String> {
+    let v = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("`{s}`: {e}"))?;
+    T::try_from(v).map_err(|_| format!("`{s}` out of range"))
+}
+
+#[derive(Args, Debug)]
+pub struct GenerateArgs {
+    /// frame seq number (decimal or 0x hex)
+    #[arg(long, default_value = "1", value_parser = parse_num::<u16>, requires = "frame")]
+    seq: u16,
+    /// message type (decimal or 0x hex)
+    #[arg(short = 't', long = "type", default_value = "0xFF", value_parser = parse_num::<u8>, requires = "frame")]
+    msg_type: u8,
+    /// header part byte (decimal or 0x hex)
+    #[arg(long, default_value = "0", value_parser = parse_num::<u8>, requires = "frame")]
+    part: u8,
+    /// payload data as hex
+    #[arg(long, conflicts_with_all = ["file", "pattern"], requires = "frame")]
+    hex: Option<String>,
+    /// payload data read from a file
+    #[arg(long, conflicts_with = "pattern", requires = "frame")]
+    file: Option<PathBuf>,
+    /// generated payload data
+    #[arg(long, value_enum, default_value_t = Pattern::Counter, requires = "frame")]
+    pattern: Pattern,
+    /// length of the generated data
+    #[arg(short, long, default_value_t = 250)]
+    length: usize,
+    /// pad the frame until its checksum has this value (decimal or 0x hex)
+    #[arg(short, long, value_parser = parse_num::<u8>)]
+    checksum: Option<u8>,
+    /// frame output format
+    #[arg(short, long, value_enum, default_value_t = Format::Hex, requires = "frame")]
+    format: Format,
+    /// build a frame with the sender's encoder from the frame options, without it an ASCII
+    /// digit pattern with an alphanumeric checksum is generated
+    #[arg(short = 'b', long, visible_alias = "bin")]
+    frame: bool,
+}
+
+pub(crate) fn generate(args: GenerateArgs) -> Result<(), Box<dyn Error>> {
+    if !args.frame {
+        generate_ascii(args.length);
+        return Ok(());
+    }
+    let data = if let Some(hex) = &args.hex {
+        hex::decode(hex.trim())?
+    } else if let Some(file) = &args.file {
+        std::fs::read(file)?
+    } else {
+        pattern(args.pattern, args.length)
+    };
+    let mut payload = Vec::with_capacity(data.len() + 2);
+    payload.push(args.msg_type);
+    payload.push(args.part);
+    payload.extend_from_slice(&data);
+
+    let wire = encode_frame(args.seq, &payload, args.checksum).wire();
+    let mut out = std::io::stdout().lock();
+    match args.format {
+        Format::Hex => writeln!(out, "{}", hex::encode(&wire))?,
+        Format::Bin => out.write_all(&wire)?,
+        Format::C => {
+            writeln!(out, "const uint8_t frame[{}] = {{", wire.len())?;
+            write_array_body(&mut out, &wire)?;
+            writeln!(out, "}};")?;
+        }
+        Format::Rust => {
+            writeln!(out, "const FRAME: [u8; {}] = [", wire.len())?;
+            write_array_body(&mut out, &wire)?;
+            writeln!(out, "];")?;
+        }
+    }
+    Ok(())
+}
+
+fn pattern(pattern: Pattern, length: usize) -> Vec<u8> {
+    match pattern {
+        Pattern::Counter => (0..length).map(|i| i as u8).collect(),
+        Pattern::Digits => (1..=length).map(|i| b'0' + (i % 10) as u8).collect(),
+    }
+}
+
+fn write_array_body(out: &mut impl Write, wire: &[u8]) -> std::io::Result<()> {
+    for line in wire.chunks(12) {
+        let bytes: Vec<String> = line.iter().map(|b| format!("0x{b:02X},")).collect();
+        writeln!(out, "    {}", bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+fn generate_ascii(length: usize) {
+    let mut csum: u32 = 0;
+    for i in 1..length {
+        print!("{}", i % 10);
+        csum += 0x30 + (i % 10) as u32;
+        csum &= 0xff;
+    }
+    while !(csum as u8).is_ascii_alphanumeric() {
+        print!("0");
+        csum += 0x30;
+        csum &= 0xff;
+    }
+    println!("{}", (csum as u8) as char);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_matches_sender() {
+        // seq 0x0104 is sent low byte first and gets escaped
+        let frame = encode_frame(0x0104, &[0x7E, 0x00, 0x02, 0x1B], None);
+        assert_eq!(hex::encode(&frame.body), "1b34017e00021b1b");
+        assert_eq!(frame.payload_len, 5);
+        let wire = frame.wire();
+        assert_eq!(wire.last(), Some(&0x04));
+        let csum = frame.body.iter().fold(0u8, |c, b| c.wrapping_add(*b));
+        assert_eq!(frame.csum, csum);
+
+        let forced = encode_frame(1, &[0xFF, 0x00], Some(0x10));
+        assert_eq!(forced.csum, 0x10);
+    }
+
+    #[test]
+    fn test_parse_num() {
+        assert_eq!(parse_num::<u8>("0x7E"), Ok(0x7E));
+        assert_eq!(parse_num::<u16>("258"), Ok(258));
+        assert!(parse_num::<u8>("0x1FF").is_err());
+    }
+}
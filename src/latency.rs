@@ -0,0 +1,188 @@
+use std::{collections::BTreeMap, fmt::Display, time::Duration};
+
+use log::info;
+
+// log-linear buckets: values below 2^SUB_BITS us are exact,
+// above that every power of two is split in 2^SUB_BITS sub-buckets (~3% error)
+const SUB_BITS: u32 = 5;
+const SUB_COUNT: usize = 1 << SUB_BITS;
+
+// payload size buckets (upper bound in bytes) used for the per size breakdown
+const SIZE_BUCKETS: [usize; 6] = [16, 64, 128, 256, 512, usize::MAX];
+
+#[inline]
+fn bucket_index(v: u64) -> usize {
+    if v < SUB_COUNT as u64 {
+        v as usize
+    } else {
+        let shift = 63 - v.leading_zeros() - SUB_BITS;
+        let mantissa = (v >> shift) as usize - SUB_COUNT;
+        (shift as usize + 1) * SUB_COUNT + mantissa
+    }
+}
+
+#[inline]
+fn bucket_upper(idx: usize) -> u64 {
+    if idx < SUB_COUNT {
+        idx as u64
+    } else {
+        let shift = idx / SUB_COUNT - 1;
+        let mantissa = (idx % SUB_COUNT + SUB_COUNT) as u64;
+        ((mantissa + 1) << shift) - 1
+    }
+}
+
+/// Latency histogram with microsecond resolution
+#[derive(Default, Debug, Clone)]
+pub(crate) struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, d: Duration) {
+        let v = d.as_micros().min(u64::MAX as u128) as u64;
+        let idx = bucket_index(v);
+        if idx >= self.buckets.len() {
+            self.buckets.resize(idx + 1, 0);
+        }
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.max = self.max.max(v);
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    /// value at percentile `p` (0.0 - 100.0), rounded up to its bucket upper bound
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (idx, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(bucket_upper(idx).min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n:{} p50:{:?} p90:{:?} p99:{:?} max:{:?}",
+            self.count,
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.max()
+        )
+    }
+}
+
+/// Send to ACK latency, overall and per payload size bucket
+#[derive(Default, Debug)]
+pub(crate) struct LatencyStats {
+    all: Histogram,
+    by_size: BTreeMap<usize, Histogram>,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, payload_len: usize, d: Duration) {
+        self.all.record(d);
+        let bucket = *SIZE_BUCKETS.iter().find(|&&b| payload_len <= b).unwrap();
+        self.by_size.entry(bucket).or_default().record(d);
+    }
+
+    pub fn all(&self) -> &Histogram {
+        &self.all
+    }
+
+    /// p50/p99 per payload size bucket, short enough for the periodic STATS line
+    pub fn size_summary(&self) -> String {
+        let buckets: Vec<String> = self
+            .by_size
+            .iter()
+            .map(|(&upper, hist)| {
+                format!(
+                    "{}:{}/{:?}/{:?}",
+                    size_label(upper),
+                    hist.count,
+                    hist.percentile(50.0),
+                    hist.percentile(99.0)
+                )
+            })
+            .collect();
+        buckets.join(" ")
+    }
+
+    pub fn log_summary(&self) {
+        info!("RTT SUMMARY: all {}", self.all);
+        for (&upper, hist) in &self.by_size {
+            info!("RTT SUMMARY: {:>9} {}", size_label(upper), hist);
+        }
+    }
+}
+
+/// `lower-upperB` of the size bucket ending at `upper`, `lower+B` for the last one
+fn size_label(upper: usize) -> String {
+    let lower = SIZE_BUCKETS
+        .iter()
+        .take_while(|&&b| b < upper)
+        .last()
+        .map_or(0, |b| b + 1);
+    if upper == usize::MAX {
+        format!("{lower}+B")
+    } else {
+        format!("{lower}-{upper}B")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket_roundtrip() {
+        for v in [0, 1, 31, 32, 33, 63, 64, 1000, 65_535, 3_000_000] {
+            let idx = bucket_index(v);
+            assert!(bucket_upper(idx) >= v);
+            if idx > 0 {
+                assert!(bucket_upper(idx - 1) < v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut hist = Histogram::default();
+        for ms in 1..=100 {
+            hist.record(Duration::from_millis(ms));
+        }
+        assert_eq!(hist.count, 100);
+        assert_eq!(hist.max(), Duration::from_millis(100));
+        let p50 = hist.percentile(50.0).as_micros();
+        assert!((50_000..=52_000).contains(&p50), "p50 {p50}");
+        let p99 = hist.percentile(99.0).as_micros();
+        assert!((99_000..=100_000).contains(&p99), "p99 {p99}");
+    }
+
+    #[test]
+    fn test_size_summary() {
+        let mut stats = LatencyStats::default();
+        stats.record(10, Duration::from_millis(2));
+        stats.record(100, Duration::from_millis(5));
+        stats.record(1000, Duration::from_millis(9));
+        assert_eq!(
+            stats.size_summary(),
+            "0-16B:1/2ms/2ms 65-128B:1/5ms/5ms 513+B:1/9ms/9ms"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace, warn};
+
+use crate::{
+    retry::RetryPolicy,
+    seq::next_seq,
+    test_serial::{decode_frame, encode_frame, Frame, AT_CMD},
+    ConnectArgs,
+};
+
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+// unclaimed frames kept, the mesh keeps pushing while we wait
+const BACKLOG_SIZE: usize = 256;
+
+/// the serial port or a simulated device, reads time out with `ErrorKind::TimedOut`
+pub(crate) trait Port: Read + Write + Send {}
+
+impl<T: Read + Write + Send + ?Sized> Port for T {}
+
+/// request/response session over the serial port for the one-shot subcommands
+pub(crate) struct Link {
+    serial: Box<dyn Port>,
+    policy: RetryPolicy,
+    seq_no: u16,
+    rbuf: Vec<u8>,
+    // frames received while waiting for something else
+    backlog: VecDeque<Frame>,
+}
+
+impl Link {
+    pub fn open(args: &ConnectArgs, policy: RetryPolicy) -> Result<Self, serialport::Error> {
+        let serial = serialport::new(&args.port, args.baud)
+            .timeout(READ_TIMEOUT)
+            .open()?;
+        Ok(Self::new(Box::new(serial), policy))
+    }
+
+    pub fn new(serial: Box<dyn Port>, policy: RetryPolicy) -> Self {
+        Self {
+            serial,
+            policy,
+            seq_no: 0,
+            rbuf: Vec::new(),
+            backlog: VecDeque::new(),
+        }
+    }
+
+    // read what is there and queue the complete frames
+    fn poll(&mut self) {
+        let mut buf = [0; 128];
+        let n = match self.serial.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return,
+            Err(e) => {
+                warn!("LINK: read failed: {e}");
+                std::thread::sleep(READ_TIMEOUT);
+                return;
+            }
+        };
+        trace!("LINK: received {n:3}: {}", hex::encode(&buf[..n]));
+        self.rbuf.extend_from_slice(&buf[..n]);
+        while let Some(end) = self.rbuf.iter().position(|b| *b == AT_CMD) {
+            let raw: Vec<u8> = self.rbuf.drain(..=end).collect();
+            match decode_frame(&raw[..end]) {
+                Some(frame) => {
+                    if self.backlog.len() == BACKLOG_SIZE {
+                        self.backlog.pop_front();
+                    }
+                    self.backlog.push_back(frame);
+                }
+                None if end > 0 => debug!("LINK: dropped {}", hex::encode(&raw)),
+                None => (),
+            }
+        }
+    }
+
+    /// the first frame matching `want` before `deadline`, other frames stay queued
+    pub fn recv(&mut self, deadline: Instant, want: impl Fn(&Frame) -> bool) -> Option<Frame> {
+        loop {
+            if let Some(pos) = self.backlog.iter().position(&want) {
+                return self.backlog.remove(pos);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            self.poll();
+        }
+    }
+
+    /// send `payload` (message type, part and data) until it is ACKed, returns the resends
+    pub fn send(&mut self, payload: &[u8]) -> Result<u32, String> {
+        self.seq_no = next_seq(self.seq_no);
+        let seq_no = self.seq_no;
+        let wire = encode_frame(seq_no, payload, None).wire();
+        for retry in 0..=self.policy.max_retries() {
+            debug!(
+                "LINK: send SEQ:{seq_no:04X} #{retry} {}",
+                hex::encode(&wire)
+            );
+            self.serial
+                .write_all(&wire)
+                .and_then(|()| self.serial.flush())
+                .map_err(|e| format!("write failed: {e}"))?;
+            let sent_at = Instant::now();
+            let deadline = sent_at + self.policy.timeout(retry);
+            if self
+                .recv(deadline, |f| f.is_ack() && f.seq_no == seq_no)
+                .is_some()
+            {
+                self.policy.on_ack(sent_at.elapsed(), retry);
+                return Ok(retry);
+            }
+        }
+        Err(format!(
+            "SEQ:{seq_no:04X} not ACKed after {} retries",
+            self.policy.max_retries()
+        ))
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::VecDeque, fmt::Display};
+
+use crate::seq::seq_diff;
+
+// observations kept for the windowed loss rate
+const LOSS_WINDOW: usize = 100;
+// late values down to this distance can be reordered or duplicates, older ones are a reset
+const REORDER_WINDOW: i16 = -16;
+// larger forward jumps are taken as a counter reset, not as loss
+const MAX_GAP: i16 = 1000;
+
+/// how a received counter value relates to the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SeqEvent {
+    First,
+    InOrder,
+    // values skipped before this one
+    Gap(u16),
+    Duplicate,
+    // older than the last value, arrived late
+    OutOfOrder,
+    // counter restarted, the device rebooted
+    Reset { from: u16 },
+}
+
+impl SeqEvent {
+    /// the value is newer than anything seen before
+    pub fn is_new(&self) -> bool {
+        !matches!(self, SeqEvent::Duplicate | SeqEvent::OutOfOrder)
+    }
+}
+
+/// loss, duplicate, reorder and reset tracking of a 16 bit counter
+#[derive(Debug, Default)]
+pub(crate) struct SeqTracker {
+    last: Option<u16>,
+    received: u64,
+    lost: u64,
+    duplicates: u64,
+    out_of_order: u64,
+    resets: u64,
+    // values lost before each of the recent observations
+    window: VecDeque<u16>,
+    // bit n: `last - n` was received / is still missing from a gap
+    received_bits: u32,
+    missing_bits: u32,
+}
+
+impl SeqTracker {
+    pub fn on_seq(&mut self, seq: u16) -> SeqEvent {
+        let Some(last) = self.last else {
+            self.last = Some(seq);
+            self.received += 1;
+            self.received_bits = 1;
+            self.observe(0);
+            return SeqEvent::First;
+        };
+        let diff = seq_diff(last, seq);
+        let event = match diff {
+            0 => {
+                self.duplicates += 1;
+                return SeqEvent::Duplicate;
+            }
+            1 => SeqEvent::InOrder,
+            2..=MAX_GAP => SeqEvent::Gap(diff as u16 - 1),
+            REORDER_WINDOW..0 => {
+                let bit = 1 << -diff;
+                if self.missing_bits & bit != 0 {
+                    // counted as lost when the gap was seen
+                    self.missing_bits &= !bit;
+                    self.received_bits |= bit;
+                    self.out_of_order += 1;
+                    self.received += 1;
+                    self.lost = self.lost.saturating_sub(1);
+                    if let Some(lost) = self.window.iter_mut().rev().find(|l| **l > 0) {
+                        *lost -= 1;
+                    }
+                    return SeqEvent::OutOfOrder;
+                }
+                if self.received_bits & bit != 0 {
+                    self.duplicates += 1;
+                    return SeqEvent::Duplicate;
+                }
+                // never seen, the counter restarted below the last value
+                self.resets += 1;
+                SeqEvent::Reset { from: last }
+            }
+            _ => {
+                self.resets += 1;
+                SeqEvent::Reset { from: last }
+            }
+        };
+        let lost = match event {
+            SeqEvent::Gap(n) => n,
+            _ => 0,
+        };
+        if let SeqEvent::Reset { .. } = event {
+            self.received_bits = 1;
+            self.missing_bits = 0;
+        } else {
+            let shift = diff as u32;
+            self.received_bits = self.received_bits.checked_shl(shift).unwrap_or(0) | 1;
+            let gap = 1u32.checked_shl(lost as u32).map_or(u32::MAX, |b| b - 1);
+            self.missing_bits = self.missing_bits.checked_shl(shift).unwrap_or(0) | (gap << 1);
+        }
+        self.lost += lost as u64;
+        self.received += 1;
+        self.observe(lost);
+        self.last = Some(seq);
+        event
+    }
+
+    fn observe(&mut self, lost: u16) {
+        if self.window.len() == LOSS_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(lost);
+    }
+
+    /// lost / expected since the start, in percent
+    pub fn loss(&self) -> f64 {
+        percent(self.lost, self.received + self.lost)
+    }
+
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// loss over the last LOSS_WINDOW received values, in percent
+    pub fn window_loss(&self) -> f64 {
+        let lost: u64 = self.window.iter().map(|l| *l as u64).sum();
+        percent(lost, self.window.len() as u64 + lost)
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / total as f64
+    }
+}
+
+impl Display for SeqTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "RX:{:6} LOST:{:5} LOSS:{:5.1}% LAST{}:{:5.1}% DUP:{:4} OOO:{:4} RESET:{:3}",
+            self.received,
+            self.lost,
+            self.loss(),
+            LOSS_WINDOW,
+            self.window_loss(),
+            self.duplicates,
+            self.out_of_order,
+            self.resets
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seq_tracker() {
+        let mut t = SeqTracker::default();
+        assert_eq!(t.on_seq(0xFFFE), SeqEvent::First);
+        assert_eq!(t.on_seq(0xFFFF), SeqEvent::InOrder);
+        // wraps without a reset
+        assert_eq!(t.on_seq(0x0002), SeqEvent::Gap(2));
+        assert_eq!(t.on_seq(0x0002), SeqEvent::Duplicate);
+        assert_eq!(t.on_seq(0x0001), SeqEvent::OutOfOrder);
+        assert_eq!(t.lost, 1);
+        assert_eq!(t.received, 4);
+        assert_eq!(t.loss(), 20.0);
+        assert_eq!(t.last, Some(0x0002));
+        assert_eq!(t.on_seq(0x8000), SeqEvent::Reset { from: 0x0002 });
+        assert_eq!(t.on_seq(0x0001), SeqEvent::Reset { from: 0x8000 });
+        assert_eq!(t.resets, 2);
+        assert!(!SeqEvent::OutOfOrder.is_new());
+    }
+
+    #[test]
+    fn test_seq_tracker_late_duplicate() {
+        let mut t = SeqTracker::default();
+        t.on_seq(10);
+        assert_eq!(t.on_seq(13), SeqEvent::Gap(2));
+        assert_eq!(t.on_seq(11), SeqEvent::OutOfOrder);
+        // already recovered and received before the gap
+        assert_eq!(t.on_seq(11), SeqEvent::Duplicate);
+        assert_eq!(t.on_seq(10), SeqEvent::Duplicate);
+        assert_eq!((t.lost, t.out_of_order, t.duplicates), (1, 1, 2));
+        assert_eq!(t.window.iter().sum::<u16>(), 1);
+        assert_eq!(t.on_seq(12), SeqEvent::OutOfOrder);
+        assert_eq!(t.lost, 0);
+    }
+
+    #[test]
+    fn test_seq_tracker_early_reboot() {
+        let mut t = SeqTracker::default();
+        t.on_seq(5);
+        t.on_seq(6);
+        t.on_seq(7);
+        // restarted at 1 while the counter was below the reorder window
+        assert_eq!(t.on_seq(1), SeqEvent::Reset { from: 7 });
+        assert_eq!(t.on_seq(1), SeqEvent::Duplicate);
+        assert_eq!(t.on_seq(2), SeqEvent::InOrder);
+        assert_eq!((t.resets, t.lost, t.duplicates), (1, 0, 1));
+    }
+}
@@ -1,6 +1,4 @@
-#![feature(vec_push_within_capacity)]
-
-use std::error::Error;
+use std::{error::Error, path::PathBuf};
 
 use clap::{Args, Parser, Subcommand};
 use flexi_logger::{Age, Cleanup, Criterion, DeferredNow, Duplicate, FileSpec, Logger, Naming};
@@ -13,12 +11,42 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Device registry (TOML or CSV), reloaded when the file changes, devices.toml when present
+    #[arg(long, global = true, env = "ESP_DEVICES")]
+    devices: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+mod call;
+mod config;
+mod confirm;
+mod db;
+mod esp_log;
+mod fanout;
+mod fault;
+mod generate;
+mod latency;
+mod link;
+mod loss;
+mod metrics;
+mod neighbours;
+mod ota;
+mod push_ack;
+mod registry;
+mod relay;
+mod retry;
+mod role;
+mod route;
+mod seq;
+mod series;
+mod signal;
 mod test_esp;
 mod test_serial;
+mod topology;
+mod watchdog;
+mod window;
 
 #[derive(Args)]
 pub struct ConnectArgs {
@@ -28,35 +56,94 @@ pub struct ConnectArgs {
     baud: u32,
 }
 
+#[derive(Args)]
+pub struct TestArgs {
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    #[arg(long)]
+    no_send: bool,
+    #[arg(long)]
+    load_send: bool,
+    #[arg(long)]
+    at_cmd: bool,
+    #[arg(long, value_parser, num_args = 0.., value_delimiter = ' ')]
+    send: Vec<String>,
+    #[arg(long, value_parser, num_args = 0.., value_delimiter = ' ')]
+    send_time: Vec<u64>,
+    #[arg(long)]
+    esp_test: bool,
+    /// frames allowed in flight before waiting for their ACKs
+    #[arg(long, default_value_t = 1)]
+    window: usize,
+    #[clap(flatten)]
+    retry_args: retry::RetryArgs,
+    #[clap(flatten)]
+    fault_args: fault::FaultArgs,
+    #[clap(flatten)]
+    push_ack_args: push_ack::PushAckArgs,
+    #[clap(flatten)]
+    topology_args: topology::TopologyArgs,
+    #[clap(flatten)]
+    route_args: route::RouteArgs,
+    #[clap(flatten)]
+    watchdog_args: watchdog::WatchdogArgs,
+    #[clap(flatten)]
+    relay_args: relay::RelayArgs,
+    #[clap(flatten)]
+    call_args: call::CallArgs,
+    #[clap(flatten)]
+    confirm_args: confirm::ConfirmArgs,
+    #[clap(flatten)]
+    esp_log_args: esp_log::EspLogArgs,
+    /// minutes between mesh summaries, 0 for the exit summary only
+    #[arg(long, default_value_t = 0)]
+    summary_every: u64,
+    #[clap(flatten)]
+    metrics_args: metrics::MetricsArgs,
+    #[clap(flatten)]
+    series_args: series::SeriesArgs,
+    #[clap(flatten)]
+    db_args: db::DbArgs,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Generators
     Generate {
-        #[arg(short, long, default_value_t = 250)]
-        length: usize,
-        #[arg(short, long)]
-        bin: bool,
-        #[arg(short, long)]
-        checksum: Option<u8>,
+        #[clap(flatten)]
+        generate_args: generate::GenerateArgs,
+    },
+    /// Write, read back and diff device configs
+    Config {
+        #[clap(flatten)]
+        config_args: config::ConfigArgs,
     },
     /// show all serial ports
     Devs {},
+    /// Ask every device for its neighbour table and print the RSSI matrix
+    Neighbours {
+        #[clap(flatten)]
+        neighbours_args: neighbours::NeighboursArgs,
+    },
+    /// Stream a firmware image to a device
+    Ota {
+        #[clap(flatten)]
+        ota_args: ota::OtaArgs,
+    },
+    /// Query runs recorded with `test --db`
+    Report {
+        #[clap(flatten)]
+        report_args: db::ReportArgs,
+    },
+    /// Send one command to devices, a group or `all`
+    Send {
+        #[clap(flatten)]
+        send_args: fanout::SendArgs,
+    },
     /// Test serial port (read/write)
     Test {
         #[clap(flatten)]
-        connect_args: ConnectArgs,
-        #[arg(long)]
-        no_send: bool,
-        #[arg(long)]
-        load_send: bool,
-        #[arg(long)]
-        at_cmd: bool,
-        #[arg(long, value_parser, num_args = 0.., value_delimiter = ' ')]
-        send: Vec<String>,
-        #[arg(long, value_parser, num_args = 0.., value_delimiter = ' ')]
-        send_time: Vec<u64>,
-        #[arg(long)]
-        esp_test: bool,
+        test_args: Box<TestArgs>,
     },
 }
 
@@ -71,7 +158,7 @@ pub fn logging_format(
         "{} {} {}",
         now.format("%Y-%m-%d %H:%M:%S%.6f"),
         level,
-        &record.args()
+        record.args()
     )
 }
 fn main() -> Result<(), Box<dyn Error>> {
@@ -96,6 +183,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         .duplicate_to_stderr(Duplicate::Warn)
         .start()?;
 
+    let devices = cli.devices.or_else(|| {
+        let default = PathBuf::from(registry::DEFAULT_DEVICES);
+        default.exists().then_some(default)
+    });
+    if let Some(devices) = devices {
+        registry::load(&devices)?;
+        registry::watch(devices);
+    }
+
     match cli.command {
         Some(Commands::Devs {}) => {
             let ports = serialport::available_ports().expect("No ports found!");
@@ -103,34 +199,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                 println!("{}", p.port_name);
             }
         }
-        Some(Commands::Test {
-            connect_args,
-            no_send,
-            load_send,
-            at_cmd,
-            send,
-            send_time,
-            esp_test,
-        }) => test_serial::test(
-            connect_args,
-            no_send,
-            load_send,
-            at_cmd,
-            send,
-            send_time,
-            esp_test,
-        ),
-        Some(Commands::Generate {
-            length,
-            bin,
-            checksum,
-        }) => {
-            if bin {
-                test_serial::generate_bin(length, checksum);
-            } else {
-                test_serial::generate(length);
-            }
-        }
+        Some(Commands::Test { test_args }) => test_serial::test(*test_args),
+        Some(Commands::Generate { generate_args }) => generate::generate(generate_args)?,
+        Some(Commands::Report { report_args }) => db::report(report_args)?,
+        Some(Commands::Config { config_args }) => config::config(config_args)?,
+        Some(Commands::Neighbours { neighbours_args }) => neighbours::neighbours(neighbours_args)?,
+        Some(Commands::Ota { ota_args }) => ota::ota(ota_args)?,
+        Some(Commands::Send { send_args }) => fanout::send(send_args)?,
         None => {}
     }
     Ok(())
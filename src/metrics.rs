@@ -0,0 +1,191 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use clap::Args;
+use log::{error, info, warn};
+
+// a client that stops sending or reading must not hold up the next scrape
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct MetricsArgs {
+    /// serve Prometheus metrics on this address, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    samples: Vec<(String, f64)>,
+}
+
+/// collects samples and renders them grouped by metric in Prometheus text format
+#[derive(Default)]
+pub(crate) struct Metrics {
+    families: Vec<Family>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    fn add(
+        &mut self,
+        name: &'static str,
+        kind: Kind,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let family = match self.families.iter_mut().position(|f| f.name == name) {
+            Some(i) => &mut self.families[i],
+            None => {
+                self.families.push(Family {
+                    name,
+                    help,
+                    kind,
+                    samples: Vec::new(),
+                });
+                self.families.last_mut().unwrap()
+            }
+        };
+        family.samples.push((labels, value));
+    }
+
+    pub fn counter(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.add(name, Kind::Counter, help, labels, value);
+    }
+
+    pub fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.add(name, Kind::Gauge, help, labels, value);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for family in &self.families {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", family.name, kind);
+            for (labels, value) in &family.samples {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", family.name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", family.name, labels, value);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn respond(mut stream: TcpStream, render: &impl Fn() -> Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    if path == "/metrics" || path == "/" {
+        let body = render().render();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+    }
+}
+
+/// answer scrapes in a background thread, `render` collects the current values
+pub(crate) fn serve(addr: SocketAddr, render: impl Fn() -> Metrics + Send + 'static) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("METRICS: cannot listen on {addr}: {e}");
+            return;
+        }
+    };
+    info!("METRICS: listening on http://{addr}/metrics");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|stream| respond(stream, &render)) {
+                warn!("METRICS: scrape failed: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::default();
+        metrics.counter(
+            "esp_sent_total",
+            "frames sent",
+            &[("name", "Bed \"1\"")],
+            3.0,
+        );
+        metrics.gauge("serial_inflight", "frames in flight", &[], 1.0);
+        metrics.counter("esp_sent_total", "frames sent", &[("name", "Bed 2")], 4.0);
+        assert_eq!(
+            metrics.render(),
+            "# HELP esp_sent_total frames sent\n\
+             # TYPE esp_sent_total counter\n\
+             esp_sent_total{name=\"Bed \\\"1\\\"\"} 3\n\
+             esp_sent_total{name=\"Bed 2\"} 4\n\
+             # HELP serial_inflight frames in flight\n\
+             # TYPE serial_inflight gauge\n\
+             serial_inflight 1\n"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::{
+    link::Link,
+    registry::{self, Mac},
+    retry::{RetryArgs, RetryPolicy},
+    test_esp::{MSG_TYPE_NOTIFY, NOTIFY_NEIGH_QUERY, NOTIFY_NEIGH_UPDATE},
+    ConnectArgs,
+};
+
+/// how long the replies to a neighbour query are collected
+pub(crate) const NEIGH_COLLECT: Duration = Duration::from_secs(10);
+// neighbour mac and signed RSSI byte
+const NEIGH_RECORD_SIZE: usize = 7;
+
+#[derive(Args)]
+pub struct NeighboursArgs {
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    /// seconds to collect the NEIGH_UPDATEs
+    #[arg(long, default_value_t = NEIGH_COLLECT.as_secs())]
+    timeout: u64,
+    /// write the matrix to `<PREFIX>.neighbours.csv` and `<PREFIX>.neighbours.json`
+    #[arg(long, value_name = "PREFIX")]
+    out: Option<PathBuf>,
+    #[clap(flatten)]
+    retry_args: RetryArgs,
+}
+
+fn display_name(mac: &Mac) -> String {
+    registry::name(mac).unwrap_or_else(|| hex::encode(mac))
+}
+
+/// payload of the NOTIFY asking every device for its neighbour table
+pub(crate) fn query() -> Vec<u8> {
+    vec![MSG_TYPE_NOTIFY, 0, NOTIFY_NEIGH_QUERY]
+}
+
+/// NEIGH_UPDATE notify data: type, reporting mac, then a mac and RSSI per neighbour
+pub(crate) fn parse_update(data: &[u8]) -> Option<(Mac, Vec<(Mac, i8)>)> {
+    let reporter: Mac = data.get(1..7)?.try_into().ok()?;
+    let records = &data[7..];
+    if !records.len().is_multiple_of(NEIGH_RECORD_SIZE) {
+        return None;
+    }
+    let neighbours = records
+        .chunks_exact(NEIGH_RECORD_SIZE)
+        .map(|r| (r[..6].try_into().unwrap(), r[6] as i8))
+        .collect();
+    Some((reporter, neighbours))
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct NeighbourLink {
+    pub from: String,
+    pub to: String,
+    pub rssi: i8,
+}
+
+/// neighbour tables as reported by every device, one row per reporter
+#[derive(Debug, Default)]
+pub(crate) struct NeighbourTable {
+    rows: BTreeMap<Mac, BTreeMap<Mac, i8>>,
+    query_at: Option<Instant>,
+    // reporters since the last query
+    replied: BTreeSet<Mac>,
+}
+
+impl NeighbourTable {
+    pub fn start_query(&mut self, now: Instant) {
+        self.query_at = Some(now);
+        self.replied.clear();
+    }
+
+    pub fn query_at(&self) -> Option<Instant> {
+        self.query_at
+    }
+
+    /// a NEIGH_UPDATE replaces the row of its reporter
+    pub fn on_update(&mut self, reporter: Mac, neighbours: Vec<(Mac, i8)>) {
+        self.replied.insert(reporter);
+        self.rows.insert(reporter, neighbours.into_iter().collect());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// `known` devices that did not answer the last query
+    pub fn missing<'a>(&self, known: impl IntoIterator<Item = &'a Mac>) -> Vec<Mac> {
+        known
+            .into_iter()
+            .filter(|mac| !self.replied.contains(*mac))
+            .copied()
+            .collect()
+    }
+
+    // reporters and everything they heard
+    fn macs(&self) -> Vec<Mac> {
+        let mut macs: BTreeSet<Mac> = self.rows.keys().copied().collect();
+        macs.extend(self.rows.values().flat_map(|row| row.keys().copied()));
+        macs.into_iter().collect()
+    }
+
+    pub fn links(&self) -> Vec<NeighbourLink> {
+        self.rows
+            .iter()
+            .flat_map(|(from, row)| {
+                row.iter().map(move |(to, rssi)| NeighbourLink {
+                    from: hex::encode(from),
+                    to: hex::encode(to),
+                    rssi: *rssi,
+                })
+            })
+            .collect()
+    }
+
+    /// numbered rows with the RSSI each reporter hears from the numbered columns, `-` for none,
+    /// links heard in one direction only are marked with `*`
+    pub fn matrix(&self) -> Vec<String> {
+        let macs = self.macs();
+        let mut header = format!("{:>3} {:>14}", "#", "DEVICE");
+        for i in 1..=macs.len() {
+            let _ = write!(header, " {i:>5}");
+        }
+        let mut lines = vec![header];
+        for (i, from) in macs.iter().enumerate() {
+            let mut line = format!("{:>3} {:>14}", i + 1, display_name(from));
+            let row = self.rows.get(from);
+            for to in &macs {
+                let cell = match row.and_then(|row| row.get(to)) {
+                    None => "-".to_string(),
+                    Some(rssi)
+                        if self.rows.contains_key(to) && !self.rows[to].contains_key(from) =>
+                    {
+                        format!("{rssi}*")
+                    }
+                    Some(rssi) => rssi.to_string(),
+                };
+                let _ = write!(line, " {cell:>5}");
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// matrix as CSV, reporters in rows, neighbours in columns
+    pub fn to_csv(&self) -> String {
+        let macs = self.macs();
+        let mut csv = String::from("mac");
+        for mac in &macs {
+            let _ = write!(csv, ",{}", hex::encode(mac));
+        }
+        csv.push('\n');
+        for from in &macs {
+            csv.push_str(&hex::encode(from));
+            for to in &macs {
+                let rssi = self.rows.get(from).and_then(|row| row.get(to));
+                let _ = write!(csv, ",{}", rssi.map(i8::to_string).unwrap_or_default());
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// `<prefix>.neighbours.csv` and `<prefix>.neighbours.json` next to the topology files
+    pub fn export(&self, prefix: &Path) -> std::io::Result<()> {
+        std::fs::write(prefix.with_extension("neighbours.csv"), self.to_csv())?;
+        std::fs::write(
+            prefix.with_extension("neighbours.json"),
+            serde_json::to_string_pretty(&self.links()).unwrap(),
+        )
+    }
+}
+
+/// broadcast a neighbour query, collect the updates and print the matrix
+pub fn neighbours(args: NeighboursArgs) -> Result<(), Box<dyn Error>> {
+    let policy = RetryPolicy::new(args.retry_args.clone());
+    let mut link = Link::open(&args.connect_args, policy)?;
+    let mut table = NeighbourTable::default();
+    table.start_query(Instant::now());
+    link.send(&query())?;
+    info!(
+        "NEIGHBOURS: query sent, collecting updates for {}s",
+        args.timeout
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(args.timeout);
+    while let Some(frame) = link.recv(deadline, |f| {
+        f.msg_type == MSG_TYPE_NOTIFY && f.data.first() == Some(&NOTIFY_NEIGH_UPDATE)
+    }) {
+        match parse_update(&frame.data) {
+            Some((reporter, neighbours)) => {
+                debug!(
+                    "NEIGHBOURS: {} reported {} neighbours",
+                    display_name(&reporter),
+                    neighbours.len()
+                );
+                table.on_update(reporter, neighbours);
+            }
+            None => warn!("NEIGHBOURS: bad update {}", hex::encode(&frame.data)),
+        }
+    }
+    if table.is_empty() {
+        return Err("no neighbour updates received".into());
+    }
+
+    for line in table.matrix() {
+        println!("{line}");
+    }
+    // the registry devices that should have answered
+    let known: Vec<Mac> = registry::targets(&[], Some("all"))
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .map(|device| device.mac)
+        .collect();
+    for mac in table.missing(&known) {
+        warn!("NEIGHBOURS: no update from {}", display_name(&mac));
+    }
+    if let Some(prefix) = &args.out {
+        table.export(prefix)?;
+        info!(
+            "NEIGHBOURS: matrix written to {}.neighbours.csv/.json",
+            prefix.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_updates_and_matrix() {
+        let mac = |id: u8| [0, 0, 0, 0, 0, id];
+        // device 1 hears 2 at -50 and 3 at -70
+        let data = hex::decode("05000000000001000000000002ce000000000003ba").unwrap();
+        let (reporter, neighbours) = parse_update(&data).unwrap();
+        assert_eq!(reporter, mac(1));
+        assert_eq!(neighbours, [(mac(2), -50), (mac(3), -70)]);
+        assert_eq!(parse_update(&data[..10]), None);
+
+        let mut table = NeighbourTable::default();
+        table.start_query(Instant::now());
+        table.on_update(reporter, neighbours);
+        table.on_update(mac(2), vec![(mac(1), -52)]);
+        assert_eq!(table.missing(&[mac(1), mac(2), mac(3)]), [mac(3)]);
+
+        let matrix = table.matrix();
+        assert_eq!(matrix.len(), 4);
+        assert!(matrix[1].ends_with("-   -50   -70"));
+        assert!(matrix[2].ends_with("-52     -     -"));
+        assert_eq!(table.to_csv().lines().nth(1), Some("000000000001,,-50,-70"));
+        assert_eq!(table.links().len(), 3);
+    }
+}
@@ -0,0 +1,526 @@
+//! firmware image transfer through the coordinator
+//!
+//! every REQ_OTA has an op in its first data byte and ends with the target mac:
+//! - BEGIN: image size (4), CRC-32 (4), chunk size (2); the device answers with the offset it
+//!   already holds of this image, so an interrupted transfer resumes there
+//! - DATA: offset (4) and image bytes, HDR_PART is the chunk number modulo 256
+//! - END: the device checks the CRC of the whole image
+//!
+//! RES_OTA answers BEGIN and END with the op, a status, the next offset (4) and the mac
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{Read, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::{debug, info, warn};
+use rand::Rng;
+
+use crate::{
+    link::Link,
+    push_ack::parse_percent,
+    registry::{self, Mac},
+    retry::{RetryArgs, RetryPolicy},
+    test_esp::{MSG_TYPE_REQ_OTA, MSG_TYPE_RES_OTA},
+    test_serial::{decode_frame, encode_frame, AT_CMD},
+    ConnectArgs,
+};
+
+const OP_BEGIN: u8 = 0;
+const OP_DATA: u8 = 1;
+const OP_END: u8 = 2;
+
+const STATUS_OK: u8 = 0;
+// BEGIN or END with a size or offset the device does not expect
+const STATUS_OFFSET: u8 = 1;
+const STATUS_CRC: u8 = 2;
+
+// redraw the progress bar at most this often
+const PROGRESS_EVERY: Duration = Duration::from_millis(100);
+const PROGRESS_WIDTH: usize = 30;
+
+#[derive(Args)]
+#[command(mut_arg("port", |port| port.required(false).required_unless_present("simulate")))]
+pub struct OtaArgs {
+    // None with --simulate
+    #[clap(flatten)]
+    connect_args: Option<ConnectArgs>,
+    /// firmware binary
+    #[arg(short, long)]
+    file: PathBuf,
+    /// target device, registry name or mac
+    #[arg(short, long)]
+    device: String,
+    /// image bytes per DATA frame
+    #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u16).range(16..=1024))]
+    chunk: u16,
+    /// seconds to wait for the device to answer BEGIN and END
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    /// times the transfer is resumed after a DATA frame is not ACKed
+    #[arg(long, default_value_t = 3)]
+    resumes: u32,
+    /// stream to a simulated device instead of the serial port
+    #[arg(long)]
+    simulate: bool,
+    /// percentage of frames the simulated device ignores
+    #[arg(long, default_value_t = 0.0, value_parser = parse_percent, requires = "simulate")]
+    sim_loss: f64,
+    #[clap(flatten)]
+    retry_args: RetryArgs,
+}
+
+/// CRC-32 (IEEE 802.3) as used by the ESP-IDF
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn be_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(..4)?.try_into().ok()?))
+}
+
+/// image and target of one transfer
+pub(crate) struct Transfer<'a> {
+    image: &'a [u8],
+    crc: u32,
+    chunk: usize,
+    mac: Mac,
+    timeout: Duration,
+}
+
+/// outcome of a finished transfer
+#[derive(Debug, Default)]
+pub(crate) struct OtaReport {
+    pub sent: usize,
+    pub frames: u32,
+    pub retries: u32,
+    pub resumes: u32,
+    pub elapsed: Duration,
+}
+
+impl<'a> Transfer<'a> {
+    pub fn new(image: &'a [u8], mac: Mac, chunk: usize, timeout: Duration) -> Self {
+        Self {
+            image,
+            crc: crc32(image),
+            chunk,
+            mac,
+            timeout,
+        }
+    }
+
+    fn payload(&self, op: u8, part: u8, data: &[u8]) -> Vec<u8> {
+        let mut payload = vec![MSG_TYPE_REQ_OTA, part, op];
+        payload.extend_from_slice(data);
+        payload.extend_from_slice(&self.mac);
+        payload
+    }
+
+    // send `op` and wait for its RES_OTA, the next offset on STATUS_OK
+    fn request(&self, link: &mut Link, op: u8, data: &[u8]) -> Result<usize, String> {
+        link.send(&self.payload(op, 0, data))?;
+        let mac = self.mac;
+        let res = link
+            .recv(Instant::now() + self.timeout, |f| {
+                f.msg_type == MSG_TYPE_RES_OTA
+                    && f.data.first() == Some(&op)
+                    && f.data.ends_with(&mac)
+            })
+            .ok_or("no answer from the device")?;
+        let offset = res.data.get(2..).and_then(be_u32).ok_or("short answer")? as usize;
+        match res.data[1] {
+            STATUS_OK => Ok(offset),
+            STATUS_OFFSET => Err(format!("device expects offset {offset}")),
+            STATUS_CRC => Err(format!("image CRC {:08x} does not match", self.crc)),
+            status => Err(format!("device status {status}")),
+        }
+    }
+
+    fn begin(&self, link: &mut Link) -> Result<usize, String> {
+        let mut data = Vec::with_capacity(10);
+        data.extend_from_slice(&(self.image.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.crc.to_be_bytes());
+        data.extend_from_slice(&(self.chunk as u16).to_be_bytes());
+        let offset = self.request(link, OP_BEGIN, &data)?;
+        if offset > self.image.len() {
+            return Err(format!("device resumes at {offset} past the image end"));
+        }
+        Ok(offset)
+    }
+
+    /// stream the image from where the device stands, resuming up to `resumes` times
+    pub fn run(
+        &self,
+        link: &mut Link,
+        resumes: u32,
+        progress: &mut Progress,
+    ) -> Result<OtaReport, String> {
+        let started = Instant::now();
+        let mut report = OtaReport::default();
+        'resume: loop {
+            let mut offset = self.begin(link)?;
+            if offset > 0 {
+                info!("OTA: resuming at {offset} of {}", self.image.len());
+            }
+            progress.start(offset);
+            while offset < self.image.len() {
+                let end = (offset + self.chunk).min(self.image.len());
+                let mut data = (offset as u32).to_be_bytes().to_vec();
+                data.extend_from_slice(&self.image[offset..end]);
+                let part = (offset / self.chunk) as u8;
+                match link.send(&self.payload(OP_DATA, part, &data)) {
+                    Ok(retries) => {
+                        report.frames += 1;
+                        report.retries += retries;
+                        report.sent += end - offset;
+                        offset = end;
+                        progress.update(offset);
+                    }
+                    Err(e) if report.resumes < resumes => {
+                        warn!("OTA: chunk at {offset}: {e}, resuming");
+                        report.resumes += 1;
+                        continue 'resume;
+                    }
+                    Err(e) => return Err(format!("chunk at {offset}: {e}")),
+                }
+            }
+            progress.finish();
+            self.request(link, OP_END, &[])?;
+            report.elapsed = started.elapsed();
+            return Ok(report);
+        }
+    }
+}
+
+/// progress bar with throughput on stderr
+#[derive(Debug)]
+pub(crate) struct Progress {
+    total: usize,
+    started: Instant,
+    // offset the current run started at, resumed bytes do not count for the throughput
+    from: usize,
+    drawn: Option<Instant>,
+    draw: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, draw: bool) -> Self {
+        Self {
+            total,
+            started: Instant::now(),
+            from: 0,
+            drawn: None,
+            draw,
+        }
+    }
+
+    fn start(&mut self, offset: usize) {
+        self.started = Instant::now();
+        self.from = offset;
+        self.update(offset);
+    }
+
+    fn line(&self, done: usize, elapsed: Duration) -> String {
+        let filled = done * PROGRESS_WIDTH / self.total.max(1);
+        let rate = (done - self.from) as f64 / elapsed.as_secs_f64().max(1e-3);
+        format!(
+            "[{}{}] {:3}% {:.1}/{:.1} kB {:.2} kB/s",
+            "#".repeat(filled),
+            "-".repeat(PROGRESS_WIDTH - filled),
+            done * 100 / self.total.max(1),
+            done as f64 / 1000.0,
+            self.total as f64 / 1000.0,
+            rate / 1000.0
+        )
+    }
+
+    fn update(&mut self, done: usize) {
+        let now = Instant::now();
+        if !self.draw
+            || (self.drawn.is_some_and(|at| now - at < PROGRESS_EVERY) && done < self.total)
+        {
+            return;
+        }
+        self.drawn = Some(now);
+        eprint!("\r{}", self.line(done, now - self.started));
+    }
+
+    fn finish(&mut self) {
+        if self.draw {
+            eprintln!();
+        }
+    }
+}
+
+/// device side of the transfer, keeps the image between transfers so they can resume
+#[derive(Debug)]
+pub(crate) struct OtaReceiver {
+    mac: Mac,
+    size: usize,
+    crc: u32,
+    chunk: usize,
+    image: Vec<u8>,
+    pub complete: bool,
+}
+
+impl OtaReceiver {
+    pub fn new(mac: Mac) -> Self {
+        Self {
+            mac,
+            size: 0,
+            crc: 0,
+            chunk: 0,
+            image: Vec::new(),
+            complete: false,
+        }
+    }
+
+    fn answer(&self, op: u8, status: u8) -> Vec<u8> {
+        let mut res = vec![op, status];
+        res.extend_from_slice(&(self.image.len() as u32).to_be_bytes());
+        res.extend_from_slice(&self.mac);
+        res
+    }
+
+    /// the RES_OTA data answering a REQ_OTA, None for DATA and for other devices
+    pub fn on_request(&mut self, part: u8, data: &[u8]) -> Option<Vec<u8>> {
+        let body = data.strip_suffix(&self.mac[..])?;
+        let (op, body) = body.split_first()?;
+        match *op {
+            OP_BEGIN => {
+                let size = be_u32(body)? as usize;
+                let crc = be_u32(body.get(4..)?)?;
+                let chunk = u16::from_be_bytes(body.get(8..10)?.try_into().ok()?) as usize;
+                // a different image or chunk size starts over
+                if (size, crc, chunk) != (self.size, self.crc, self.chunk) || chunk == 0 {
+                    (self.size, self.crc, self.chunk) = (size, crc, chunk);
+                    self.image.clear();
+                }
+                self.complete = false;
+                let status = if chunk == 0 { STATUS_OFFSET } else { STATUS_OK };
+                Some(self.answer(OP_BEGIN, status))
+            }
+            OP_DATA => {
+                let offset = be_u32(body)? as usize;
+                let bytes = &body[4..];
+                // resent chunks whose ACK was lost are skipped
+                if offset == self.image.len()
+                    && part == (offset / self.chunk.max(1)) as u8
+                    && offset + bytes.len() <= self.size
+                {
+                    self.image.extend_from_slice(bytes);
+                } else if offset != self.image.len() {
+                    debug!("OTA SIM: chunk at {offset}, holding {}", self.image.len());
+                }
+                None
+            }
+            OP_END => {
+                let status = if self.image.len() != self.size {
+                    STATUS_OFFSET
+                } else if crc32(&self.image) != self.crc {
+                    STATUS_CRC
+                } else {
+                    self.complete = true;
+                    STATUS_OK
+                };
+                Some(self.answer(OP_END, status))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// a device behind the coordinator, ACKs every frame and answers REQ_OTA
+pub(crate) struct SimDevice {
+    receiver: Arc<Mutex<OtaReceiver>>,
+    // percentage of frames ignored
+    loss: f64,
+    // frames handled before the device goes silent
+    silent_after: Option<usize>,
+    rbuf: Vec<u8>,
+    out: VecDeque<u8>,
+    seq_no: u16,
+}
+
+impl SimDevice {
+    pub fn new(receiver: Arc<Mutex<OtaReceiver>>, loss: f64) -> Self {
+        Self {
+            receiver,
+            loss,
+            silent_after: None,
+            rbuf: Vec::new(),
+            out: VecDeque::new(),
+            seq_no: 0,
+        }
+    }
+
+    fn on_frame(&mut self, raw: &[u8]) {
+        let Some(frame) = decode_frame(raw) else {
+            return;
+        };
+        if let Some(left) = &mut self.silent_after {
+            if *left == 0 {
+                return;
+            }
+            *left -= 1;
+        }
+        if frame.is_ack() || rand::thread_rng().gen_range(0.0..100.0) < self.loss {
+            return;
+        }
+        let ack = encode_frame(frame.seq_no, &[frame.msg_type | 0x80, frame.part], None);
+        self.out.extend(ack.wire());
+        if frame.msg_type != MSG_TYPE_REQ_OTA {
+            return;
+        }
+        if let Some(res) = self
+            .receiver
+            .lock()
+            .unwrap()
+            .on_request(frame.part, &frame.data)
+        {
+            self.seq_no = self.seq_no.wrapping_add(1);
+            let mut payload = vec![MSG_TYPE_RES_OTA, 0];
+            payload.extend_from_slice(&res);
+            self.out
+                .extend(encode_frame(self.seq_no, &payload, None).wire());
+        }
+    }
+}
+
+impl Write for SimDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.rbuf.extend_from_slice(buf);
+        while let Some(end) = self.rbuf.iter().position(|b| *b == AT_CMD) {
+            let raw: Vec<u8> = self.rbuf.drain(..=end).collect();
+            self.on_frame(&raw[..end]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SimDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.out.is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        let n = buf.len().min(self.out.len());
+        for (b, o) in buf.iter_mut().zip(self.out.drain(..n)) {
+            *b = o;
+        }
+        Ok(n)
+    }
+}
+
+/// stream a firmware image to one device
+pub fn ota(args: OtaArgs) -> Result<(), Box<dyn Error>> {
+    let image = std::fs::read(&args.file)?;
+    if image.is_empty() {
+        return Err(format!("{} is empty", args.file.display()).into());
+    }
+    let mac = match registry::find(&args.device) {
+        Some(device) => device.mac,
+        None => registry::parse_mac(&args.device)
+            .map_err(|_| format!("`{}` is neither a known device nor a mac", args.device))?,
+    };
+    let policy = RetryPolicy::new(args.retry_args.clone());
+    let mut link = match (&args.connect_args, args.simulate) {
+        (Some(connect_args), false) => Link::open(connect_args, policy)?,
+        _ => {
+            let receiver = Arc::new(Mutex::new(OtaReceiver::new(mac)));
+            Link::new(Box::new(SimDevice::new(receiver, args.sim_loss)), policy)
+        }
+    };
+
+    let transfer = Transfer::new(
+        &image,
+        mac,
+        args.chunk as usize,
+        Duration::from_secs(args.timeout),
+    );
+    info!(
+        "OTA: {} bytes CRC {:08x} to {} in {} byte chunks",
+        image.len(),
+        transfer.crc,
+        args.device,
+        args.chunk
+    );
+    let mut progress = Progress::new(image.len(), true);
+    let report = transfer.run(&mut link, args.resumes, &mut progress)?;
+    let rate = report.sent as f64 / report.elapsed.as_secs_f64().max(1e-3);
+    println!(
+        "{}: {} bytes CRC {:08x} verified in {:.1?}, {:.2} kB/s, {} frames, {} resends, {} resumes",
+        args.device,
+        image.len(),
+        transfer.crc,
+        report.elapsed,
+        rate / 1000.0,
+        report.frames,
+        report.retries,
+        report.resumes
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        retry_args: RetryArgs,
+    }
+
+    fn link(receiver: &Arc<Mutex<OtaReceiver>>, silent_after: Option<usize>) -> Link {
+        let cli = Cli::parse_from(["ota", "--ack-timeout", "20", "--max-retries", "2"]);
+        let mut device = SimDevice::new(receiver.clone(), 0.0);
+        device.silent_after = silent_after;
+        Link::new(Box::new(device), RetryPolicy::new(cli.retry_args))
+    }
+
+    #[test]
+    fn test_transfer_and_resume() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let image: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mac = [1, 2, 3, 4, 5, 6];
+        let receiver = Arc::new(Mutex::new(OtaReceiver::new(mac)));
+        let transfer = Transfer::new(&image, mac, 64, Duration::from_millis(50));
+        let mut progress = Progress::new(image.len(), false);
+
+        // the device goes silent after BEGIN and 5 chunks
+        let err = transfer.run(&mut link(&receiver, Some(6)), 0, &mut progress);
+        assert!(err.is_err());
+        assert_eq!(receiver.lock().unwrap().image.len(), 5 * 64);
+
+        let report = transfer
+            .run(&mut link(&receiver, None), 0, &mut progress)
+            .unwrap();
+        assert_eq!((report.sent, report.frames), (1000 - 5 * 64, 11));
+        let receiver = receiver.lock().unwrap();
+        assert!(receiver.complete);
+        assert_eq!(receiver.image, image);
+        assert!(progress
+            .line(500, Duration::from_secs(1))
+            .starts_with(&format!("[{}-", "#".repeat(15))));
+    }
+}
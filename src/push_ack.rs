@@ -0,0 +1,179 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::info;
+use rand::Rng;
+
+use crate::test_serial::UartVec;
+
+pub(crate) fn parse_percent(s: &str) -> Result<f64, String> {
+    let percent: f64 = s
+        .parse()
+        .map_err(|e| format!("bad percentage `{s}`: {e}"))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("percentage `{percent}` not in 0 - 100"));
+    }
+    Ok(percent)
+}
+
+#[derive(Args, Clone, Copy, Debug)]
+pub struct PushAckArgs {
+    /// answer every PUSH_NETSTAT/PUSH_GPIO with its push response, as the gateway would
+    #[arg(long, requires = "esp_test")]
+    pub push_ack: bool,
+    /// percentage of push responses not sent, to exercise the push retries
+    #[arg(long, default_value_t = 0.0, value_parser = parse_percent)]
+    pub push_ack_drop: f64,
+    /// percentage of push responses sent late
+    #[arg(long, default_value_t = 0.0, value_parser = parse_percent)]
+    pub push_ack_delay: f64,
+    /// how late a delayed push response is sent, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    pub push_ack_delay_ms: u64,
+}
+
+impl Default for PushAckArgs {
+    fn default() -> Self {
+        Self {
+            push_ack: false,
+            push_ack_drop: 0.0,
+            push_ack_delay: 0.0,
+            push_ack_delay_ms: 2000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AckAction {
+    Send,
+    Drop,
+    Delay(Duration),
+}
+
+impl Display for AckAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AckAction::Send => "sent".fmt(f),
+            AckAction::Drop => "dropped".fmt(f),
+            AckAction::Delay(delay) => write!(f, "delayed {delay:?}"),
+        }
+    }
+}
+
+/// push responses waiting for the sender, in the order they are due
+#[derive(Debug, Default)]
+pub(crate) struct PushAcker {
+    args: PushAckArgs,
+    pending: VecDeque<(Instant, UartVec)>,
+    sent: u32,
+    dropped: u32,
+    delayed: u32,
+}
+
+impl PushAcker {
+    pub fn new(args: PushAckArgs) -> Self {
+        Self {
+            args,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.args.push_ack
+    }
+
+    fn decide(&self, rng: &mut impl Rng) -> AckAction {
+        let roll = rng.gen_range(0.0..100.0);
+        if roll < self.args.push_ack_drop {
+            AckAction::Drop
+        } else if roll < self.args.push_ack_drop + self.args.push_ack_delay {
+            AckAction::Delay(Duration::from_millis(self.args.push_ack_delay_ms))
+        } else {
+            AckAction::Send
+        }
+    }
+
+    /// queue the `response` to a push unless it is dropped
+    pub fn on_push(&mut self, response: UartVec, now: Instant) -> AckAction {
+        let action = self.decide(&mut rand::thread_rng());
+        let due = match action {
+            AckAction::Drop => {
+                self.dropped += 1;
+                return action;
+            }
+            AckAction::Delay(delay) => {
+                self.delayed += 1;
+                now + delay
+            }
+            AckAction::Send => now,
+        };
+        let pos = self.pending.partition_point(|(at, _)| *at <= due);
+        self.pending.insert(pos, (due, response));
+        action
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.front().map(|(due, _)| *due)
+    }
+
+    /// the next response to send at `now`
+    pub fn pop_due(&mut self, now: Instant) -> Option<UartVec> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.sent += 1;
+        self.pending.pop_front().map(|(_, response)| response)
+    }
+
+    pub fn log_report(&self) {
+        if self.is_enabled() {
+            info!(
+                "PUSH ACK: sent:{} dropped:{} delayed:{} pending:{}",
+                self.sent,
+                self.dropped,
+                self.delayed,
+                self.pending.len()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_drop_and_delay() {
+        let t0 = Instant::now();
+        let mut acker = PushAcker::new(PushAckArgs {
+            push_ack: true,
+            push_ack_delay: 100.0,
+            push_ack_delay_ms: 500,
+            ..Default::default()
+        });
+        assert_eq!(
+            acker.on_push(vec![1], t0),
+            AckAction::Delay(Duration::from_millis(500))
+        );
+        acker.args.push_ack_delay = 0.0;
+        assert_eq!(acker.on_push(vec![2], t0), AckAction::Send);
+        // the undelayed response goes first
+        assert_eq!(acker.next_due(), Some(t0));
+        assert_eq!(acker.pop_due(t0), Some(vec![2]));
+        assert_eq!(acker.pop_due(t0), None);
+        assert_eq!(
+            acker.pop_due(t0 + Duration::from_millis(500)),
+            Some(vec![1])
+        );
+
+        acker.args.push_ack_drop = 100.0;
+        assert_eq!(acker.on_push(vec![3], t0), AckAction::Drop);
+        assert_eq!(acker.next_due(), None);
+        assert_eq!((acker.sent, acker.dropped, acker.delayed), (2, 1, 1));
+        assert!(parse_percent("101").is_err());
+    }
+}
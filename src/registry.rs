@@ -0,0 +1,421 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{LazyLock, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::call::CallEvent;
+
+const WATCH_PERIOD: Duration = Duration::from_secs(2);
+/// registry loaded from the working directory when `--devices` is not given
+pub(crate) const DEFAULT_DEVICES: &str = "devices.toml";
+/// group name matching every device but the coordinators
+pub(crate) const ALL_DEVICES: &str = "all";
+
+pub(crate) type Mac = [u8; 6];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DeviceInfo {
+    pub mac: Mac,
+    pub name: String,
+    pub role: String,
+    pub location: String,
+    // pin number -> label
+    pub pins: BTreeMap<u8, String>,
+    // pin number -> nurse-call meaning, TOML only
+    pub calls: BTreeMap<u8, CallEvent>,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    devices: HashMap<Mac, DeviceInfo>,
+}
+
+// the registry loaded with `--devices`, tests build their own `Registry`
+static REGISTRY: LazyLock<RwLock<Registry>> = LazyLock::new(Default::default);
+
+// devices.toml layout:
+//   [pins.bed]
+//   0 = "Dry1/Tamper"
+//   [calls.bed]
+//   0 = "tamper"
+//   [[device]]
+//   mac = "6867254eed84"
+//   name = "Tester Bed 103"
+//   role = "bed"
+//   location = "Room 103"
+//   pins = "bed"
+//   groups = ["ward-a", "beds"]
+//   [groups]
+//   beds = ["Tester Bed 103", "Tester Bed 105"]
+#[derive(Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    device: Vec<DeviceEntry>,
+    #[serde(default)]
+    pins: HashMap<String, BTreeMap<String, String>>,
+    // same table names as `pins`
+    #[serde(default)]
+    calls: HashMap<String, BTreeMap<String, CallEvent>>,
+    // group -> device names, added to the groups of each device
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct DeviceEntry {
+    mac: String,
+    name: String,
+    #[serde(default)]
+    role: String,
+    #[serde(default)]
+    location: String,
+    // name of a [pins.<table>]
+    pins: Option<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+pub(crate) fn parse_mac(s: &str) -> Result<Mac, Box<dyn Error>> {
+    let s: String = s.chars().filter(|c| !matches!(c, ':' | '-')).collect();
+    let bytes = hex::decode(&s)?;
+    Ok(bytes
+        .try_into()
+        .map_err(|_| format!("MAC `{s}` is not 6 bytes"))?)
+}
+
+fn parse_pins<'a>(
+    pairs: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Result<BTreeMap<u8, String>, Box<dyn Error>> {
+    pairs
+        .map(|(pin, label)| Ok((pin.trim().parse()?, label.trim().to_string())))
+        .collect()
+}
+
+impl Registry {
+    pub fn from_toml(text: &str) -> Result<Self, Box<dyn Error>> {
+        let file: RegistryFile = toml::from_str(text)?;
+        let mut tables = HashMap::new();
+        for (table, pins) in &file.pins {
+            let pins = parse_pins(pins.iter().map(|(p, l)| (p.as_str(), l.as_str())))?;
+            tables.insert(table.as_str(), pins);
+        }
+        let mut devices = HashMap::new();
+        for entry in file.device {
+            let mac = parse_mac(&entry.mac)?;
+            let pins = match &entry.pins {
+                Some(table) => tables
+                    .get(table.as_str())
+                    .cloned()
+                    .ok_or_else(|| format!("{}: unknown pin table `{table}`", entry.name))?,
+                None => BTreeMap::new(),
+            };
+            let calls = match entry.pins.as_ref().and_then(|table| file.calls.get(table)) {
+                Some(calls) => calls
+                    .iter()
+                    .map(|(pin, event)| Ok((pin.trim().parse()?, *event)))
+                    .collect::<Result<_, Box<dyn Error>>>()?,
+                None => BTreeMap::new(),
+            };
+            devices.insert(
+                mac,
+                DeviceInfo {
+                    mac,
+                    name: entry.name,
+                    role: entry.role,
+                    location: entry.location,
+                    pins,
+                    calls,
+                    groups: entry.groups,
+                },
+            );
+        }
+        let mut registry = Self { devices };
+        for (group, names) in file.groups {
+            for name in names {
+                let mac = registry
+                    .find(&name)
+                    .ok_or_else(|| format!("group {group}: unknown device `{name}`"))?
+                    .mac;
+                let groups = &mut registry.devices.get_mut(&mac).unwrap().groups;
+                if !groups.contains(&group) {
+                    groups.push(group.clone());
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    /// `mac,name,role,location,pins,groups` rows, pins as `0=Dry1/Tamper;1=Dry2`,
+    /// groups as `ward-a;beds`
+    pub fn from_csv(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut devices = HashMap::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("mac,") {
+                continue;
+            }
+            let mut cols = line.split(',').map(str::trim);
+            let mac = parse_mac(cols.next().unwrap_or_default())?;
+            let name = cols.next().ok_or_else(|| format!("no name in `{line}`"))?;
+            let role = cols.next().unwrap_or_default();
+            let location = cols.next().unwrap_or_default();
+            let pins = parse_pins(
+                cols.next()
+                    .unwrap_or_default()
+                    .split(';')
+                    .filter(|p| !p.trim().is_empty())
+                    .map(|p| p.split_once('=').unwrap_or((p, ""))),
+            )?;
+            let groups = cols
+                .next()
+                .unwrap_or_default()
+                .split(';')
+                .map(str::trim)
+                .filter(|g| !g.is_empty())
+                .map(str::to_string)
+                .collect();
+            devices.insert(
+                mac,
+                DeviceInfo {
+                    mac,
+                    name: name.to_string(),
+                    role: role.to_string(),
+                    location: location.to_string(),
+                    pins,
+                    calls: BTreeMap::new(),
+                    groups,
+                },
+            );
+        }
+        Ok(Self { devices })
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => Self::from_csv(&text),
+            _ => Self::from_toml(&text),
+        }
+    }
+
+    /// devices of `group` sorted by name, `all` is every device but the coordinators
+    fn members(&self, group: &str) -> Vec<&DeviceInfo> {
+        let mut devices: Vec<&DeviceInfo> = self
+            .devices
+            .values()
+            .filter(|d| match group {
+                ALL_DEVICES => !d.role.eq_ignore_ascii_case("coordinator"),
+                _ => d.groups.iter().any(|g| g.eq_ignore_ascii_case(group)),
+            })
+            .collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+
+    pub fn get(&self, mac: &Mac) -> Option<&DeviceInfo> {
+        self.devices.get(mac)
+    }
+
+    /// the device called `name`, `_` matching a space
+    pub fn find(&self, name: &str) -> Option<&DeviceInfo> {
+        // '_' stands for ' ' so names survive space separated CLI lists
+        let name = name.replace('_', " ");
+        self.devices
+            .values()
+            .find(|d| d.name.eq_ignore_ascii_case(&name))
+    }
+
+    pub fn name(&self, mac: &Mac) -> Option<&str> {
+        self.devices.get(mac).map(|d| d.name.as_str())
+    }
+
+    /// the devices `names` followed by the members of `group` without repeats, Err(name) for
+    /// names not in the registry
+    pub fn targets(
+        &self,
+        names: &[String],
+        group: Option<&str>,
+    ) -> Result<Vec<Result<DeviceInfo, String>>, String> {
+        let mut targets: Vec<Result<DeviceInfo, String>> = Vec::new();
+        for name in names {
+            // `Bed 103` and `Bed_103` are the same device
+            let target = self.find(name).cloned().ok_or(name.clone());
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        if let Some(group) = group {
+            let members = self.members(group);
+            if members.is_empty() {
+                return Err(format!("no devices in group `{group}`"));
+            }
+            for device in members {
+                if !targets.iter().flatten().any(|d| d.mac == device.mac) {
+                    targets.push(Ok(device.clone()));
+                }
+            }
+        }
+        Ok(targets)
+    }
+
+    /// replace `{device name}` placeholders in a hex string with the device MAC
+    pub fn expand_names(&self, hex: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(hex.len());
+        let mut rest = hex;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed `{{` in `{hex}`"))?;
+            let name = &rest[(open + 1)..(open + close)];
+            let device = self
+                .find(name)
+                .ok_or_else(|| format!("unknown device `{name}`"))?;
+            out.push_str(&rest[..open]);
+            out.push_str(&hex::encode(device.mac));
+            rest = &rest[(open + close + 1)..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// load the device registry, replacing the current one
+pub(crate) fn load(path: &Path) -> Result<(), Box<dyn Error>> {
+    let registry = Registry::from_file(path)?;
+    info!(
+        "loaded {} devices from {}",
+        registry.devices.len(),
+        path.display()
+    );
+    set(registry);
+    Ok(())
+}
+
+pub(crate) fn set(devices: Registry) {
+    *REGISTRY.write().unwrap() = devices;
+}
+
+/// reload the registry whenever the file changes
+pub(crate) fn watch(path: PathBuf) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last: Option<SystemTime> = modified(&path);
+    thread::spawn(move || loop {
+        thread::sleep(WATCH_PERIOD);
+        let now = modified(&path);
+        if now != last {
+            last = now;
+            if let Err(e) = load(&path) {
+                error!("reload {} failed, keeping old devices: {e}", path.display());
+            }
+        }
+    });
+}
+
+pub(crate) fn get(mac: &Mac) -> Option<DeviceInfo> {
+    REGISTRY.read().unwrap().get(mac).cloned()
+}
+
+pub(crate) fn find(name: &str) -> Option<DeviceInfo> {
+    REGISTRY.read().unwrap().find(name).cloned()
+}
+
+pub(crate) fn name(mac: &Mac) -> Option<String> {
+    REGISTRY.read().unwrap().name(mac).map(str::to_string)
+}
+
+pub(crate) fn targets(
+    names: &[String],
+    group: Option<&str>,
+) -> Result<Vec<Result<DeviceInfo, String>>, String> {
+    REGISTRY.read().unwrap().targets(names, group)
+}
+
+pub(crate) fn expand_names(hex: &str) -> Result<String, String> {
+    REGISTRY.read().unwrap().expand_names(hex)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_toml_and_csv() {
+        let from_toml = Registry::from_toml(
+            r#"
+            [pins.bed]
+            0 = "Dry1/Tamper"
+            6 = "CLEAR"
+
+            [[device]]
+            mac = "68:67:25:4e:ed:84"
+            name = "Tester Bed 103"
+            role = "bed"
+            location = "Room 103"
+            pins = "bed"
+            groups = ["ward-a"]
+            "#,
+        )
+        .unwrap();
+        let from_csv = Registry::from_csv(
+            "mac,name,role,location,pins,groups\n6867254eed84,Tester Bed 103,bed,Room 103,0=Dry1/Tamper;6=CLEAR,ward-a\n",
+        )
+        .unwrap();
+        let mac = [0x68, 0x67, 0x25, 0x4e, 0xed, 0x84];
+        assert_eq!(from_toml.devices[&mac], from_csv.devices[&mac]);
+        assert_eq!(from_toml.devices[&mac].pins[&6], "CLEAR");
+        assert!(from_toml.find("tester_bed_103").is_some());
+        assert_eq!(from_csv.devices[&mac].groups, ["ward-a"]);
+
+        let registry = Registry::from_toml(
+            r#"
+            [groups]
+            beds = ["Bed 103", "Bed_105"]
+
+            [[device]]
+            mac = "000000000001"
+            name = "Bed 103"
+            groups = ["beds"]
+            [[device]]
+            mac = "000000000002"
+            name = "Bed 105"
+            [[device]]
+            mac = "000000000003"
+            name = "COORDINATOR"
+            role = "coordinator"
+            "#,
+        )
+        .unwrap();
+        let names = |group| -> Vec<&str> {
+            registry
+                .members(group)
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect()
+        };
+        assert_eq!(names("beds"), ["Bed 103", "Bed 105"]);
+        assert_eq!(registry.devices[&[0, 0, 0, 0, 0, 1]].groups, ["beds"]);
+        assert_eq!(names(ALL_DEVICES), ["Bed 103", "Bed 105"]);
+        assert_eq!(registry.name(&[0, 0, 0, 0, 0, 2]), Some("Bed 105"));
+        assert_eq!(
+            registry.expand_names("7E{Bed_105}"),
+            Ok("7E000000000002".to_string())
+        );
+        let targets = registry
+            .targets(
+                &["Bed 105".into(), "Bed 109".into(), "bed_105".into()],
+                Some("beds"),
+            )
+            .unwrap();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[1], Err("Bed 109".to_string()));
+        assert!(Registry::from_toml("[groups]\nbeds = [\"Bed 109\"]").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+
+#[derive(Args, Clone, Copy, Debug)]
+pub struct RelayArgs {
+    /// minutes of NETSTATs the relay and failed-queued rates are computed over
+    #[arg(long, default_value_t = 10)]
+    pub relay_window: u64,
+    /// share of all relayed traffic that makes a router a hotspot
+    #[arg(long, default_value_t = 0.3)]
+    pub hotspot_share: f64,
+}
+
+impl Default for RelayArgs {
+    fn default() -> Self {
+        Self {
+            relay_window: 10,
+            hotspot_share: 0.3,
+        }
+    }
+}
+
+impl RelayArgs {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(60 * self.relay_window)
+    }
+}
+
+/// relayed and failed-queued frames of one device over the relay window
+#[derive(Debug, Default)]
+pub(crate) struct RelayLoad {
+    // NETSTAT time, relay_req + relay_ntfy, failed_queued
+    samples: VecDeque<(Instant, u32, u32)>,
+}
+
+impl RelayLoad {
+    /// the counters of a new NETSTAT, deltas since the previous one
+    pub fn on_netstat(&mut self, now: Instant, relayed: u32, failed_queued: u32, window: Duration) {
+        self.samples.push_back((now, relayed, failed_queued));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _, _)| now.saturating_duration_since(*at) > window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    // per minute over the samples after the first, whose deltas cover time before the window
+    fn rate(&self, pick: impl Fn(&(Instant, u32, u32)) -> u32) -> Option<f64> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let minutes = last.0.saturating_duration_since(first.0).as_secs_f64() / 60.0;
+        if minutes == 0.0 {
+            return None;
+        }
+        Some(self.samples.iter().skip(1).map(pick).sum::<u32>() as f64 / minutes)
+    }
+
+    /// relayed requests and notifies per minute
+    pub fn relay_rate(&self) -> Option<f64> {
+        self.rate(|s| s.1)
+    }
+
+    /// frames failed in the send queue per minute
+    pub fn failed_queued_rate(&self) -> Option<f64> {
+        self.rate(|s| s.2)
+    }
+
+    /// more queue failures in the second half of the window than in the first
+    pub fn failed_queued_rising(&self) -> bool {
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else {
+            return false;
+        };
+        let middle = first.0 + last.0.saturating_duration_since(first.0) / 2;
+        let (early, late) =
+            self.samples
+                .iter()
+                .skip(1)
+                .fold((0, 0), |(early, late), (at, _, failed)| {
+                    if *at <= middle {
+                        (early + failed, late)
+                    } else {
+                        (early, late + failed)
+                    }
+                });
+        late > early
+    }
+}
+
+/// one router in the relay report
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RelayRow {
+    pub name: String,
+    // devices whose route to the coordinator passes through this one
+    pub downstream: usize,
+    pub relay_rate: f64,
+    pub failed_queued_rate: f64,
+    pub rising: bool,
+}
+
+/// Pearson correlation of relay rate and failed-queued rate across the devices
+pub(crate) fn correlation(rows: &[RelayRow]) -> Option<f64> {
+    if rows.len() < 3 {
+        return None;
+    }
+    let n = rows.len() as f64;
+    let mean_r = rows.iter().map(|r| r.relay_rate).sum::<f64>() / n;
+    let mean_f = rows.iter().map(|r| r.failed_queued_rate).sum::<f64>() / n;
+    let (mut cov, mut var_r, mut var_f) = (0.0, 0.0, 0.0);
+    for row in rows {
+        let (dr, df) = (row.relay_rate - mean_r, row.failed_queued_rate - mean_f);
+        cov += dr * df;
+        var_r += dr * dr;
+        var_f += df * df;
+    }
+    if var_r == 0.0 || var_f == 0.0 {
+        return None;
+    }
+    Some(cov / (var_r * var_f).sqrt())
+}
+
+/// table of the routers sorted by relay rate, with their share of all relayed traffic
+pub(crate) fn report(mut rows: Vec<RelayRow>, args: &RelayArgs) -> Vec<String> {
+    let total: f64 = rows.iter().map(|r| r.relay_rate).sum();
+    rows.sort_by(|a, b| b.relay_rate.total_cmp(&a.relay_rate));
+    let mut lines = vec![format!(
+        "{:>14} {:>10} {:>9} {:>6} {:>9} {:>6} {:>7}",
+        "DEVICE", "DOWNSTREAM", "RELAY/MIN", "SHARE%", "FAILQ/MIN", "RISING", "HOTSPOT"
+    )];
+    for row in &rows {
+        let share = if total > 0.0 {
+            row.relay_rate / total
+        } else {
+            0.0
+        };
+        let hotspot = match (share >= args.hotspot_share, row.rising) {
+            (true, true) => "QUEUE",
+            (true, false) => "yes",
+            (false, _) => "-",
+        };
+        lines.push(format!(
+            "{:>14} {:>10} {:>9.1} {:>6.1} {:>9.2} {:>6} {:>7}",
+            row.name,
+            row.downstream,
+            row.relay_rate,
+            100.0 * share,
+            row.failed_queued_rate,
+            if row.rising { "yes" } else { "-" },
+            hotspot
+        ));
+    }
+    match correlation(&rows) {
+        Some(r) => lines.push(format!("relay load vs failed queued correlation: {r:+.2}")),
+        None => lines.push("relay load vs failed queued correlation: -".to_string()),
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rates_and_report() {
+        let window = Duration::from_secs(600);
+        let t0 = Instant::now();
+        let at = |min: u64| t0 + Duration::from_secs(60 * min);
+        let mut load = RelayLoad::default();
+        assert_eq!(load.relay_rate(), None);
+        for (min, relayed, failed) in [(0, 99, 9), (1, 10, 0), (2, 20, 0), (3, 30, 2), (4, 40, 4)] {
+            load.on_netstat(at(min), relayed, failed, window);
+        }
+        assert_eq!(load.relay_rate(), Some(25.0));
+        assert_eq!(load.failed_queued_rate(), Some(1.5));
+        assert!(load.failed_queued_rising());
+        // the first samples leave the window
+        load.on_netstat(at(13), 0, 0, window);
+        assert_eq!(load.samples.len(), 3);
+        assert!(!load.failed_queued_rising());
+
+        let row = |name: &str, relay_rate, failed_queued_rate, rising| RelayRow {
+            name: name.into(),
+            downstream: 1,
+            relay_rate,
+            failed_queued_rate,
+            rising,
+        };
+        let rows = vec![
+            row("a", 10.0, 0.1, false),
+            row("b", 60.0, 2.0, true),
+            row("c", 30.0, 0.5, false),
+        ];
+        assert!(correlation(&rows).unwrap() > 0.9);
+        let lines = report(rows, &RelayArgs::default());
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].trim_start().starts_with('b'));
+        assert!(lines[1].contains(" 60.0 ") && lines[1].ends_with("QUEUE"));
+        assert!(lines[2].ends_with("yes"));
+        assert!(lines[4].ends_with("+0.98"));
+    }
+}
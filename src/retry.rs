@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+use rand::Rng;
+
+// RFC 6298 bounds and gains, relaxed for the serial link
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const RTT_ALPHA: f64 = 1.0 / 8.0;
+const RTT_BETA: f64 = 1.0 / 4.0;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    /// same timeout for every retry
+    Fixed,
+    /// timeout doubles with every retry
+    Exponential,
+    /// exponential with random jitter between the base and the doubled timeout
+    Jittered,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RetryArgs {
+    /// ACK timeout in milliseconds (initial timeout in adaptive mode)
+    #[arg(long, default_value_t = 3000)]
+    ack_timeout: u64,
+    /// resends of a frame before it is abandoned
+    #[arg(long, default_value_t = 20)]
+    max_retries: u32,
+    /// timeout growth between retries
+    #[arg(long, value_enum, default_value_t = Backoff::Fixed)]
+    backoff: Backoff,
+    /// estimate the ACK timeout from measured RTT (TCP RTO style)
+    #[arg(long)]
+    adaptive: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct RetryPolicy {
+    args: RetryArgs,
+    srtt: Option<f64>,
+    rttvar: f64,
+    rto: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(args: RetryArgs) -> Self {
+        let rto = Duration::from_millis(args.ack_timeout);
+        Self {
+            args,
+            srtt: None,
+            rttvar: 0.0,
+            rto,
+        }
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.args.max_retries
+    }
+
+    /// ACK wait for the given retry (0 for the first send)
+    pub fn timeout(&self, retry: u32) -> Duration {
+        let base = if self.args.adaptive {
+            self.rto
+        } else {
+            Duration::from_millis(self.args.ack_timeout)
+        };
+        let doubled = base.saturating_mul(1 << retry.min(16)).min(MAX_RTO.max(base));
+        match self.args.backoff {
+            Backoff::Fixed => base,
+            Backoff::Exponential => doubled,
+            Backoff::Jittered if doubled > base => rand::thread_rng().gen_range(base..=doubled),
+            Backoff::Jittered => base,
+        }
+    }
+
+    /// feed a measured RTT; resent frames are ambiguous and skipped (Karn's algorithm)
+    pub fn on_ack(&mut self, rtt: Duration, retries: u32) {
+        if retries > 0 {
+            return;
+        }
+        let r = rtt.as_secs_f64();
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2.0;
+            }
+            Some(srtt) => {
+                self.rttvar = (1.0 - RTT_BETA) * self.rttvar + RTT_BETA * (srtt - r).abs();
+                self.srtt = Some((1.0 - RTT_ALPHA) * srtt + RTT_ALPHA * r);
+            }
+        }
+        let rto = self.srtt.unwrap() + 4.0 * self.rttvar;
+        self.rto = Duration::from_secs_f64(rto).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(backoff: Backoff, adaptive: bool) -> RetryPolicy {
+        RetryPolicy::new(RetryArgs {
+            ack_timeout: 1000,
+            max_retries: 5,
+            backoff,
+            adaptive,
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        let fixed = policy(Backoff::Fixed, false);
+        assert_eq!(fixed.timeout(3), Duration::from_secs(1));
+        let exp = policy(Backoff::Exponential, false);
+        assert_eq!(exp.timeout(0), Duration::from_secs(1));
+        assert_eq!(exp.timeout(3), Duration::from_secs(8));
+        assert_eq!(exp.timeout(30), MAX_RTO);
+        let jit = policy(Backoff::Jittered, false);
+        let t = jit.timeout(2);
+        assert!(t >= Duration::from_secs(1) && t <= Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_adaptive_rto() {
+        let mut p = policy(Backoff::Fixed, true);
+        assert_eq!(p.timeout(0), Duration::from_secs(1));
+        for _ in 0..50 {
+            p.on_ack(Duration::from_millis(100), 0);
+        }
+        assert_eq!(p.timeout(0), MIN_RTO);
+        p.on_ack(Duration::from_secs(10), 3);
+        assert_eq!(p.timeout(0), MIN_RTO);
+        for _ in 0..50 {
+            p.on_ack(Duration::from_millis(800), 0);
+        }
+        let rto = p.timeout(0).as_millis();
+        assert!((800..1000).contains(&rto), "rto {rto}");
+    }
+}
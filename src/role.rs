@@ -0,0 +1,94 @@
+use std::{collections::BTreeSet, fmt::Display};
+
+use crate::registry::Mac;
+
+/// place of a device in the mesh, from its NETSTATs and the routes of the others
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    Coordinator,
+    Router,
+    #[default]
+    Leaf,
+}
+
+impl Role {
+    /// `routes_others` when another device uses it as next node, `relayed` when it relayed frames
+    pub fn classify(coordinator: bool, routes_others: bool, relayed: bool) -> Self {
+        if coordinator {
+            Role::Coordinator
+        } else if routes_others || relayed {
+            Role::Router
+        } else {
+            Role::Leaf
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Coordinator => "coordinator",
+            Role::Router => "router",
+            Role::Leaf => "leaf",
+        }
+        .fmt(f)
+    }
+}
+
+/// network wide counters a coordinator reports in place of its own link statistics
+#[derive(Debug, Default)]
+pub(crate) struct CoordinatorStats {
+    pub rx_ntfy: u32,
+    pub rx_bcast: u32,
+    pub rx_direct: u32,
+    pub relay_req: u32,
+    pub relay_ntfy: u32,
+    // devices whose route ends at this coordinator
+    pub heard: BTreeSet<Mac>,
+}
+
+impl CoordinatorStats {
+    /// the counters of a coordinator NETSTAT
+    pub fn on_netstat(&mut self, msg: &[u8]) {
+        self.rx_ntfy += msg[6] as u32;
+        self.rx_bcast += msg[7] as u32;
+        self.rx_direct += msg[8] as u32;
+        self.relay_req += msg[9] as u32;
+        self.relay_ntfy += msg[10] as u32;
+    }
+
+    /// `mac` pushed a NETSTAT routed to this coordinator, true when heard for the first time
+    pub fn on_heard(&mut self, mac: Mac) -> bool {
+        self.heard.insert(mac)
+    }
+
+    /// the route of `mac` ends at another coordinator now, true when it was heard here
+    pub fn on_moved(&mut self, mac: &Mac) -> bool {
+        self.heard.remove(mac)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classify_and_counters() {
+        assert_eq!(Role::classify(true, true, true), Role::Coordinator);
+        assert_eq!(Role::classify(false, true, false), Role::Router);
+        assert_eq!(Role::classify(false, false, true), Role::Router);
+        assert_eq!(Role::classify(false, false, false), Role::Leaf);
+        assert!(Role::Coordinator < Role::Leaf);
+        assert_eq!(Role::Router.to_string(), "router");
+
+        let mut stats = CoordinatorStats::default();
+        stats.on_netstat(&[0, 0xFF, 0, 0, 0, 0, 3, 2, 1, 0, 5]);
+        stats.on_netstat(&[0, 0xFF, 0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!((stats.rx_ntfy, stats.rx_bcast, stats.relay_ntfy), (4, 2, 6));
+        assert!(stats.on_heard([0, 0, 0, 0, 0, 1]));
+        assert!(!stats.on_heard([0, 0, 0, 0, 0, 1]));
+        assert_eq!(stats.heard.len(), 1);
+        assert!(stats.on_moved(&[0, 0, 0, 0, 0, 1]));
+        assert!(!stats.on_moved(&[0, 0, 0, 0, 0, 1]));
+    }
+}
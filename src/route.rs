@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+
+use crate::{
+    registry::Mac,
+    signal::{SignalArgs, SignalStats},
+};
+
+// parent changes kept per device
+const HISTORY_SIZE: usize = 100;
+
+#[derive(Args, Clone, Debug)]
+pub struct RouteArgs {
+    /// parent changes within --flap-window that flag a device as flapping
+    #[arg(long, default_value_t = 3)]
+    pub flap_count: usize,
+    /// flap detection window in minutes
+    #[arg(long, default_value_t = 10)]
+    pub flap_window: u64,
+    #[clap(flatten)]
+    pub signal: SignalArgs,
+}
+
+impl Default for RouteArgs {
+    fn default() -> Self {
+        Self {
+            flap_count: 3,
+            flap_window: 10,
+            signal: SignalArgs::default(),
+        }
+    }
+}
+
+/// link quality and time spent on one parent
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LinkStats {
+    pub rssi: SignalStats,
+    pub snr: SignalStats,
+    time_on: Duration,
+}
+
+impl LinkStats {
+    /// mean RSSI as reported, the raw byte
+    pub fn rssi(&self) -> Option<u8> {
+        self.rssi.mean().map(|m| m.round() as u8)
+    }
+
+    /// mean SNR as reported, the raw byte
+    pub fn snr(&self) -> Option<u8> {
+        self.snr.mean().map(|m| m.round() as u8)
+    }
+}
+
+/// the moving average of a link left or re-entered its degradation margin
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LinkAlert {
+    pub metric: &'static str,
+    pub degraded: bool,
+    pub ema: f64,
+    pub baseline: f64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RouteChange {
+    pub at: Instant,
+    pub from: Mac,
+    pub to: Mac,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RouteHistory {
+    args: RouteArgs,
+    // current parent and when it was taken
+    parent: Option<(Mac, Instant)>,
+    links: BTreeMap<Mac, LinkStats>,
+    changes: VecDeque<RouteChange>,
+    flapping: bool,
+}
+
+/// what a NETSTAT parent report changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RouteEvent {
+    First,
+    Same,
+    Changed { from: Mac },
+}
+
+impl RouteHistory {
+    pub fn new(args: &RouteArgs) -> Self {
+        Self {
+            args: args.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// record the parent reported by a NETSTAT
+    pub fn on_parent(&mut self, parent: Mac, now: Instant) -> RouteEvent {
+        let event = match self.parent {
+            None => RouteEvent::First,
+            Some((old, _)) if old == parent => return RouteEvent::Same,
+            Some((old, since)) => {
+                self.links.entry(old).or_default().time_on += now.saturating_duration_since(since);
+                if self.changes.len() == HISTORY_SIZE {
+                    self.changes.pop_front();
+                }
+                self.changes.push_back(RouteChange {
+                    at: now,
+                    from: old,
+                    to: parent,
+                });
+                RouteEvent::Changed { from: old }
+            }
+        };
+        self.links.entry(parent).or_default();
+        self.parent = Some((parent, now));
+        event
+    }
+
+    /// add an RSSI/SNR sample for the link to the current parent
+    pub fn on_quality(&mut self, rssi: u8, snr: u8, now: Instant) -> Vec<LinkAlert> {
+        let Some((parent, _)) = self.parent else {
+            return Vec::new();
+        };
+        let link = self.links.entry(parent).or_default();
+        let mut alerts = Vec::new();
+        for (metric, stats, value) in [("RSSI", &mut link.rssi, rssi), ("SNR", &mut link.snr, snr)]
+        {
+            if let Some(degraded) = stats.record(value as f64, now, &self.args.signal) {
+                alerts.push(LinkAlert {
+                    metric,
+                    degraded,
+                    ema: stats.ema(),
+                    baseline: stats.baseline().unwrap_or_default(),
+                });
+            }
+        }
+        alerts
+    }
+
+    pub fn signal_args(&self) -> &SignalArgs {
+        &self.args.signal
+    }
+
+    pub fn link(&self) -> Option<&LinkStats> {
+        self.parent.and_then(|(mac, _)| self.links.get(&mac))
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = (&Mac, &LinkStats)> {
+        self.links.iter()
+    }
+
+    pub fn changes(&self) -> impl Iterator<Item = &RouteChange> {
+        self.changes.iter()
+    }
+
+    /// time spent on `parent`, including the running stretch
+    pub fn time_on(&self, parent: &Mac, now: Instant) -> Duration {
+        let done = self
+            .links
+            .get(parent)
+            .map(|l| l.time_on)
+            .unwrap_or_default();
+        match self.parent {
+            Some((mac, since)) if mac == *parent => done + now.saturating_duration_since(since),
+            _ => done,
+        }
+    }
+
+    /// changes inside the flap window ending at `now`
+    pub fn recent_changes(&self, now: Instant) -> usize {
+        let window = Duration::from_secs(self.args.flap_window * 60);
+        self.changes
+            .iter()
+            .filter(|c| now.saturating_duration_since(c.at) <= window)
+            .count()
+    }
+
+    /// re-evaluate flapping, returns the new state when it changed
+    pub fn check_flapping(&mut self, now: Instant) -> Option<bool> {
+        let flapping = self.recent_changes(now) >= self.args.flap_count;
+        if flapping == self.flapping {
+            return None;
+        }
+        self.flapping = flapping;
+        Some(flapping)
+    }
+
+    pub fn is_flapping(&self) -> bool {
+        self.flapping
+    }
+
+    pub fn flap_window(&self) -> u64 {
+        self.args.flap_window
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_history_and_flapping() {
+        let mut route = RouteHistory::new(&RouteArgs {
+            flap_count: 3,
+            flap_window: 1,
+            ..Default::default()
+        });
+        let (a, b) = ([0, 0, 0, 0, 0, 1], [0, 0, 0, 0, 0, 2]);
+        let t0 = Instant::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+
+        assert_eq!(route.on_parent(a, at(0)), RouteEvent::First);
+        route.on_quality(216, 10, at(0));
+        assert_eq!(route.on_parent(a, at(5)), RouteEvent::Same);
+        assert_eq!(route.on_parent(b, at(10)), RouteEvent::Changed { from: a });
+        route.on_quality(186, 4, at(10));
+        // quality of the old parent survives the change
+        assert_eq!(route.links[&a].rssi(), Some(216));
+        assert_eq!(route.link().unwrap().snr(), Some(4));
+        assert_eq!(route.check_flapping(at(10)), None);
+
+        route.on_parent(a, at(20));
+        route.on_parent(b, at(30));
+        assert_eq!(route.check_flapping(at(30)), Some(true));
+        assert_eq!(route.time_on(&a, at(30)), Duration::from_secs(20));
+        assert_eq!(route.time_on(&b, at(40)), Duration::from_secs(20));
+        assert_eq!(route.changes().count(), 3);
+        // changes age out of the window
+        assert_eq!(route.check_flapping(at(85)), Some(false));
+    }
+}
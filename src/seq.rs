@@ -0,0 +1,30 @@
+// wrap-aware arithmetic for the 16 bit sequence counters
+
+/// signed distance from `from` to `to`, positive when `to` is newer
+#[inline]
+pub(crate) fn seq_diff(from: u16, to: u16) -> i16 {
+    to.wrapping_sub(from) as i16
+}
+
+/// next frame seq number, 0 is reserved for "nothing pending"
+#[inline]
+pub(crate) fn next_seq(seq: u16) -> u16 {
+    match seq.wrapping_add(1) {
+        0 => 1,
+        s => s,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seq_wrap() {
+        assert_eq!(next_seq(1), 2);
+        assert_eq!(next_seq(0xFFFF), 1);
+        assert_eq!(seq_diff(0xFFFE, 0x0002), 4);
+        assert_eq!(seq_diff(0x0002, 0xFFFE), -4);
+        assert_eq!(seq_diff(10, 10), 0);
+    }
+}
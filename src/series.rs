@@ -0,0 +1,235 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeriesFormat {
+    /// comma separated with a header line
+    Csv,
+    /// one JSON object per line
+    Jsonl,
+    /// InfluxDB line protocol
+    Influx,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct SeriesArgs {
+    /// append every NETSTAT sample to this file
+    #[arg(long)]
+    pub samples: Option<PathBuf>,
+    /// sample file format, guessed from the extension when not set
+    #[arg(long, value_enum)]
+    pub samples_format: Option<SeriesFormat>,
+}
+
+/// one decoded NETSTAT, deltas since the previous one
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct NetstatSample {
+    // host time, seconds since the epoch
+    pub ts: f64,
+    pub mac: String,
+    pub name: String,
+    pub rssi: u8,
+    pub snr: u8,
+    pub resent: u8,
+    pub failed_queued: u8,
+    pub failed: u8,
+    pub sent: u8,
+    pub rx_ntfy: u8,
+    pub rx_bcast: u8,
+    pub rx_direct: u8,
+    pub relay_req: u8,
+    pub relay_ntfy: u8,
+    pub net_stat_ts: u16,
+    pub push_id: u16,
+    pub next_node: String,
+}
+
+const CSV_HEADER: &str = "ts,mac,name,rssi,snr,resent,failed_queued,failed,sent,rx_ntfy,rx_bcast,rx_direct,relay_req,relay_ntfy,net_stat_ts,push_id,next_node";
+
+impl NetstatSample {
+    /// `stats` are the first 11 NETSTAT bytes
+    pub fn new(
+        mac: String,
+        name: String,
+        stats: &[u8],
+        net_stat_ts: u16,
+        push_id: u16,
+        next_node: String,
+    ) -> Self {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        Self {
+            ts,
+            mac,
+            name,
+            rssi: stats[0],
+            snr: stats[1],
+            resent: stats[2],
+            failed_queued: stats[3],
+            failed: stats[4],
+            sent: stats[5],
+            rx_ntfy: stats[6],
+            rx_bcast: stats[7],
+            rx_direct: stats[8],
+            relay_req: stats[9],
+            relay_ntfy: stats[10],
+            net_stat_ts,
+            push_id,
+            next_node,
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, u64); 13] {
+        [
+            ("rssi", self.rssi as u64),
+            ("snr", self.snr as u64),
+            ("resent", self.resent as u64),
+            ("failed_queued", self.failed_queued as u64),
+            ("failed", self.failed as u64),
+            ("sent", self.sent as u64),
+            ("rx_ntfy", self.rx_ntfy as u64),
+            ("rx_bcast", self.rx_bcast as u64),
+            ("rx_direct", self.rx_direct as u64),
+            ("relay_req", self.relay_req as u64),
+            ("relay_ntfy", self.relay_ntfy as u64),
+            ("net_stat_ts", self.net_stat_ts as u64),
+            ("push_id", self.push_id as u64),
+        ]
+    }
+
+    fn to_csv(&self) -> String {
+        let quote = |s: &str| {
+            if s.contains([',', '"', '\n']) {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.to_string()
+            }
+        };
+        let counters: Vec<String> = self.counters().iter().map(|(_, v)| v.to_string()).collect();
+        format!(
+            "{:.3},{},{},{},{}",
+            self.ts,
+            self.mac,
+            quote(&self.name),
+            counters.join(","),
+            quote(&self.next_node)
+        )
+    }
+
+    fn to_influx(&self) -> String {
+        let tag = |s: &str| {
+            s.replace(',', "\\,")
+                .replace('=', "\\=")
+                .replace(' ', "\\ ")
+        };
+        let fields: Vec<String> = self
+            .counters()
+            .iter()
+            .map(|(k, v)| format!("{k}={v}i"))
+            .collect();
+        // empty tag values are invalid line protocol, leave those tags out
+        let tags: String = [
+            ("mac", &self.mac),
+            ("name", &self.name),
+            ("next_node", &self.next_node),
+        ]
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| format!(",{k}={}", tag(v)))
+        .collect();
+        format!(
+            "netstat{tags} {} {}",
+            fields.join(","),
+            (self.ts * 1e9) as u64
+        )
+    }
+}
+
+pub(crate) struct SeriesWriter {
+    format: SeriesFormat,
+    out: BufWriter<File>,
+}
+
+impl SeriesWriter {
+    pub fn open(path: &Path, format: Option<SeriesFormat>) -> std::io::Result<Self> {
+        let format = format.unwrap_or_else(|| match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => SeriesFormat::Csv,
+            Some("influx" | "lp" | "line") => SeriesFormat::Influx,
+            _ => SeriesFormat::Jsonl,
+        });
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut out = BufWriter::new(file);
+        if format == SeriesFormat::Csv && empty {
+            writeln!(out, "{CSV_HEADER}")?;
+        }
+        Ok(Self { format, out })
+    }
+
+    pub fn write(&mut self, sample: &NetstatSample) -> std::io::Result<()> {
+        let line = match self.format {
+            SeriesFormat::Csv => sample.to_csv(),
+            SeriesFormat::Jsonl => serde_json::to_string(sample)?,
+            SeriesFormat::Influx => sample.to_influx(),
+        };
+        writeln!(self.out, "{line}")?;
+        // soak tests are read while they run
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let mut sample = NetstatSample::new(
+            "6867254eed84".into(),
+            "Tester Bed 103".into(),
+            &[40, 10, 0, 0, 1, 12, 0, 0, 12, 0, 1],
+            0x1234,
+            7,
+            "COORDINATOR".into(),
+        );
+        sample.ts = 1.5;
+        assert_eq!(
+            sample.to_csv(),
+            "1.500,6867254eed84,Tester Bed 103,40,10,0,0,1,12,0,0,12,0,1,4660,7,COORDINATOR"
+        );
+        assert_eq!(
+            CSV_HEADER.split(',').count(),
+            sample.to_csv().split(',').count()
+        );
+        assert!(sample.to_influx().starts_with(
+            "netstat,mac=6867254eed84,name=Tester\\ Bed\\ 103,next_node=COORDINATOR rssi=40i,"
+        ));
+        assert!(sample.to_influx().ends_with("push_id=7i 1500000000"));
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&sample).unwrap()).unwrap();
+        assert_eq!(json["net_stat_ts"], 4660);
+
+        // an unregistered coordinator is named by its mac and has no next node
+        let mut coordinator = NetstatSample::new(
+            "6867254d6258".into(),
+            "6867254d6258".into(),
+            &[0, 0xFF, 0, 0, 0, 2, 0, 0, 0, 0, 0],
+            1,
+            1,
+            String::new(),
+        );
+        coordinator.ts = 2.0;
+        let line = coordinator.to_influx();
+        assert!(line.starts_with("netstat,mac=6867254d6258,name=6867254d6258 rssi=0i,snr=255i,"));
+        assert!(!line.contains("next_node"));
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+
+// width of a histogram bucket, in units of the reported byte
+const HIST_STEP: i32 = 5;
+// samples kept for the trend windows
+const RECENT_SIZE: usize = 4096;
+
+#[derive(Args, Clone, Debug)]
+pub struct SignalArgs {
+    /// weight of a new RSSI/SNR sample in the moving average
+    #[arg(long, default_value_t = 0.1)]
+    pub ema_alpha: f64,
+    /// RSSI/SNR trend windows in minutes
+    #[arg(long = "trend-window", default_values_t = [10, 60])]
+    pub trend_windows: Vec<u64>,
+    /// samples averaged into a link's baseline
+    #[arg(long, default_value_t = 20)]
+    pub baseline_samples: u64,
+    /// drop of the moving average below the baseline that raises a degradation alert, in units
+    /// of the reported RSSI/SNR byte
+    #[arg(long, default_value_t = 6.0)]
+    pub degrade_margin: f64,
+}
+
+impl Default for SignalArgs {
+    fn default() -> Self {
+        Self {
+            ema_alpha: 0.1,
+            trend_windows: vec![10, 60],
+            baseline_samples: 20,
+            degrade_margin: 6.0,
+        }
+    }
+}
+
+/// statistics of one RSSI or SNR series, samples are the raw unsigned bytes the ESP reports
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SignalStats {
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    // sum of squared differences from the mean (Welford)
+    m2: f64,
+    ema: f64,
+    baseline: Option<f64>,
+    degraded: bool,
+    histogram: BTreeMap<i32, u64>,
+    recent: VecDeque<(Instant, f64)>,
+}
+
+impl SignalStats {
+    /// add a sample, returns the new degradation state when it changed
+    pub fn record(&mut self, value: f64, now: Instant, args: &SignalArgs) -> Option<bool> {
+        self.count += 1;
+        if self.count == 1 {
+            (self.min, self.max, self.ema) = (value, value, value);
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+            self.ema += args.ema_alpha * (value - self.ema);
+        }
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        *self
+            .histogram
+            .entry((value as i32).div_euclid(HIST_STEP) * HIST_STEP)
+            .or_default() += 1;
+        if self.recent.len() == RECENT_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((now, value));
+
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            None if self.count >= args.baseline_samples => {
+                self.baseline = Some(self.mean);
+                return None;
+            }
+            None => return None,
+        };
+        let drop = baseline - self.ema;
+        // half the margin as hysteresis so a noisy link does not toggle
+        let degraded = if self.degraded {
+            drop > args.degrade_margin / 2.0
+        } else {
+            drop >= args.degrade_margin
+        };
+        if degraded == self.degraded {
+            return None;
+        }
+        self.degraded = degraded;
+        Some(degraded)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.mean)
+    }
+
+    pub fn stddev(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.count - 1) as f64).sqrt()
+        }
+    }
+
+    pub fn ema(&self) -> f64 {
+        self.ema
+    }
+
+    pub fn baseline(&self) -> Option<f64> {
+        self.baseline
+    }
+
+    /// least squares slope in reported units per minute over the samples of the last `window`
+    pub fn trend(&self, window: Duration, now: Instant) -> Option<f64> {
+        let points: Vec<(f64, f64)> = self
+            .recent
+            .iter()
+            .filter(|(at, _)| now.saturating_duration_since(*at) <= window)
+            .map(|(at, v)| {
+                (
+                    -(now.saturating_duration_since(*at).as_secs_f64() / 60.0),
+                    *v,
+                )
+            })
+            .collect();
+        if points.len() < 2 {
+            return None;
+        }
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_v = points.iter().map(|(_, v)| v).sum::<f64>() / n;
+        let var: f64 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+        if var == 0.0 {
+            return None;
+        }
+        let cov: f64 = points
+            .iter()
+            .map(|(t, v)| (t - mean_t) * (v - mean_v))
+            .sum();
+        Some(cov / var)
+    }
+
+    /// `mean sd min max ema trends`
+    pub fn describe(&self, args: &SignalArgs, now: Instant) -> String {
+        let Some(mean) = self.mean() else {
+            return "-".to_string();
+        };
+        let mut out = format!(
+            "mean:{:.1} sd:{:.1} min:{} max:{} ema:{:.1}",
+            mean,
+            self.stddev(),
+            self.min,
+            self.max,
+            self.ema
+        );
+        if let Some(baseline) = self.baseline {
+            let _ = write!(out, " base:{baseline:.1}");
+        }
+        for minutes in &args.trend_windows {
+            if let Some(trend) = self.trend(Duration::from_secs(60 * minutes), now) {
+                let _ = write!(out, " {minutes}m:{trend:+.2}/min");
+            }
+        }
+        out
+    }
+
+    /// `bucket:count` pairs, buckets are HIST_STEP units wide
+    pub fn histogram(&self) -> String {
+        self.histogram
+            .iter()
+            .map(|(bucket, n)| format!("{bucket}:{n}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats_and_degradation() {
+        let args = SignalArgs {
+            baseline_samples: 4,
+            ema_alpha: 0.5,
+            ..Default::default()
+        };
+        let mut stats = SignalStats::default();
+        let t0 = Instant::now();
+        let at = |min: u64| t0 + Duration::from_secs(60 * min);
+        for (i, v) in [206.0, 204.0, 202.0, 204.0].into_iter().enumerate() {
+            assert_eq!(stats.record(v, at(i as u64), &args), None);
+        }
+        assert_eq!(stats.mean(), Some(204.0));
+        assert!((stats.stddev() - 1.633).abs() < 0.001);
+        assert_eq!(stats.baseline(), Some(204.0));
+        assert_eq!(stats.histogram(), "200:3 205:1");
+
+        // link gets worse by 2 a minute
+        let mut alerts = Vec::new();
+        for i in 4..12 {
+            let v = 204.0 - 2.0 * (i - 3) as f64;
+            alerts.extend(stats.record(v, at(i), &args));
+        }
+        assert_eq!(alerts, [true]);
+        let trend = stats.trend(Duration::from_secs(5 * 60), at(11)).unwrap();
+        assert!((trend + 2.0).abs() < 0.001, "trend {trend}");
+        for i in 12..20 {
+            alerts.extend(stats.record(204.0, at(i), &args));
+        }
+        assert_eq!(alerts, [true, false]);
+        assert_eq!(stats.count(), 20);
+    }
+}
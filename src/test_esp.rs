@@ -1,5 +1,21 @@
 use log::*;
-use std::{collections::HashMap, fmt::Display, time::{Instant, Duration}};
+use std::{collections::{HashMap, HashSet}, fmt::Display, time::{Instant, Duration}};
+
+use crate::{
+    call::{self, CallTracker},
+    confirm::{CommandVerifier, ConfirmArgs, Outcome, PinCommand},
+    db::DbWriter,
+    loss::{SeqEvent, SeqTracker},
+    metrics::Metrics,
+    neighbours::{self, NeighbourTable},
+    registry::{self, Mac},
+    relay::{self, RelayArgs, RelayLoad, RelayRow},
+    role::{CoordinatorStats, Role},
+    route::{LinkStats, RouteArgs, RouteEvent, RouteHistory},
+    series::{NetstatSample, SeriesWriter},
+    topology::{Node, NodeInput, Topology},
+    watchdog::{Health, Liveness, WatchdogArgs},
+};
 
 // message header offsets and size
 // const HDR_SEQ_LB: usize = 0;
@@ -14,12 +30,17 @@ const MSG_TYPE_RES: u8 = 0x20;
 // responses 0x20 - 0x3f = (0x00 - 0x1F | MSG_TYPE_RES)
 pub const MSG_TYPE_REQ_CONFIG: u8 = 0x00;
 pub const MSG_TYPE_RES_CONFIG: u8 = MSG_TYPE_REQ_CONFIG | MSG_TYPE_RES;
+// firmware image transfer, see ota.rs
+pub(crate) const MSG_TYPE_REQ_OTA: u8 = 0x10;
+pub(crate) const MSG_TYPE_RES_OTA: u8 = MSG_TYPE_REQ_OTA | MSG_TYPE_RES;
 // push info 0x40 - 0x5F = (MSG_TYPE_PUSH | 0x00 - 0x1F)
 // push responses 0x60 - 0x7F = (MSG_TYPE_PUSH | MSG_TYPE_RES | 0x00 - 0x1F)
 const MSG_TYPE_PUSH: u8 = 0x40;
 const MSG_TYPE_PUSH_NETSTAT: u8 = MSG_TYPE_PUSH | 0x01;
 const STAT_SIZE: usize = 13;
 const MSG_TYPE_PUSH_GPIO: u8 = MSG_TYPE_PUSH | 0x02;
+// push id and mac ending every push
+const PUSH_TRAILER_SIZE: usize = 8;
 
 /// ntfy msg type, bcast only
 pub const MSG_TYPE_NOTIFY: u8 = 0x7E;
@@ -28,23 +49,46 @@ pub const MSG_TYPE_NOTIFY: u8 = 0x7E;
 
 // begin notify types
 // notifies 0x00 - 0x3F
-// const NOTIFY_CONFIG_CHANGED: u8 = 0x01;
-// const NOTIFY_PIN_LED: u8 = 0x02;
+pub(crate) const NOTIFY_CONFIG_CHANGED: u8 = 0x01;
+pub(crate) const NOTIFY_PIN_LED: u8 = 0x02;
 // const NOTIFY_RGB_LED: u8 = 0x03;
-// const NOTIFY_NEIGH_QUERY: u8 = 0x04;
-// const NOTIFY_NEIGH_UPDATE: u8 = 0x05;
+pub(crate) const NOTIFY_NEIGH_QUERY: u8 = 0x04;
+pub(crate) const NOTIFY_NEIGH_UPDATE: u8 = 0x05;
 // push notifies are 0x40 - 0x5F = (MSG_TYPE_PUSH | 0x00 - 0x1F)
 // end notify types
 
-const ESP_NAMES: phf::Map<&'static str,&'static str> = phf::phf_map! {
-    "6867254e3ff0" => "Tester Bed 105",
-    "7cdfa1dee298" => "Tester Call Lights",
-    "6867254d6258" => "COORDINATOR",
-    "6867254f88f8" => "Tester 6867254f88f8",
-    "6867254eed84" => "Tester Bed 103",
-    "7cdfa1dee03c" => "Tester 7cdfa1dee03c",
-    "a0764ead1d30" => "Tester Bed 108",
-};
+/// sender and `pin, level` pairs of a PUSH_GPIO, sent directly or as a push notify
+pub(crate) fn gpio_push(msg_type: u8, data: &[u8]) -> Option<(Mac, &[u8])> {
+    match msg_type {
+        MSG_TYPE_PUSH_GPIO if data.len() >= PUSH_TRAILER_SIZE => {
+            let mac = data[(data.len() - 6)..].try_into().ok()?;
+            Some((mac, &data[..(data.len() - PUSH_TRAILER_SIZE)]))
+        }
+        MSG_TYPE_NOTIFY if data.len() >= 9 && data[0] == MSG_TYPE_PUSH_GPIO => {
+            Some((data[1..7].try_into().ok()?, &data[7..(data.len() - 2)]))
+        }
+        _ => None,
+    }
+}
+
+/// payload of the push response to a PUSH_NETSTAT/PUSH_GPIO: type, part, push id and mac,
+/// also for pushes the coordinator forwards in a NOTIFY (type, mac, data, push id)
+pub(crate) fn push_response(msg_type: u8, data: &[u8]) -> Option<Vec<u8>> {
+    let (push_type, push_id, mac) = match msg_type {
+        MSG_TYPE_PUSH_NETSTAT | MSG_TYPE_PUSH_GPIO if data.len() >= PUSH_TRAILER_SIZE => {
+            let trailer = &data[(data.len() - PUSH_TRAILER_SIZE)..];
+            (msg_type, &trailer[..2], &trailer[2..])
+        }
+        MSG_TYPE_NOTIFY if data.len() >= 9 && matches!(data[0], MSG_TYPE_PUSH_NETSTAT | MSG_TYPE_PUSH_GPIO) => {
+            (data[0], &data[(data.len() - 2)..], &data[1..7])
+        }
+        _ => return None,
+    };
+    let mut payload = vec![push_type | MSG_TYPE_RES, 0];
+    payload.extend_from_slice(push_id);
+    payload.extend_from_slice(mac);
+    Some(payload)
+}
 
 #[derive(Debug)]
 struct Timestamp(Instant);
@@ -77,18 +121,17 @@ impl AsRef<[u8]> for MacAddr {
 
 impl Display for MacAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let hex_code = hex::encode(self);
-        if let Some(name) = ESP_NAMES.get(&hex_code) {
+        if let Some(name) = registry::name(&self.0) {
             name.fmt(f)
         } else {
-            hex_code.fmt(f)
+            hex::encode(self).fmt(f)
         }
     }
 }
 
 impl std::fmt::Debug for MacAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("MacAddr").field(&format!("{}",&self)).finish()
+        f.debug_tuple("MacAddr").field(&format!("{}",self)).finish()
     }
 }
 
@@ -97,86 +140,237 @@ struct EspDevice {
     addr: MacAddr,
     net_stat_ts: u16,
     last_push_id: u16,
+    netstat_seq: SeqTracker,
+    push_seq: SeqTracker,
     last_seen: Timestamp,
+    // first seen or last reboot
+    up_since: Timestamp,
     last_seen_gap: Duration,
+    liveness: Liveness,
+    role: Role,
+    // only filled while the device is a coordinator
+    coordinator: CoordinatorStats,
     next_node: Option<MacAddr>,
     total_resent: u32,
     total_sent: u32,
     total_failed: u32,
     total_failed_queued: u32,
-    rssi: u32,
-    snr: u32,
-    rssi_cnt: u32,
+    route: RouteHistory,
+    db: DbWriter,
     total_rx_ntfy: u32,
     total_rx_bcast: u32,
     total_rx_direct: u32,
     total_relay_req: u32,
     total_relay_ntfy: u32,
+    relay: RelayLoad,
 }
 
 impl EspDevice {
-    fn new(addr: MacAddr) -> Self {
+    fn new(addr: MacAddr, route_args: &RouteArgs, db: &DbWriter) -> Self {
+        let role = match registry::get(&addr.0) {
+            Some(dev) => {
+                info!("{:>14}>ESP New device role:{} location:{}", addr, dev.role, dev.location);
+                dev.role
+            }
+            None => {
+                warn!("{:>14}>ESP New device not in registry", addr);
+                String::new()
+            }
+        };
+        db.device(&addr.0, &addr.to_string(), &role);
         Self {
             addr,
+            route: RouteHistory::new(route_args),
+            db: db.clone(),
             ..Default::default()
         }
     }
 
-    fn decode_netstat(&mut self, msg: &[u8]) {
-        let next_node = MacAddr::from(msg);
-        let is_coordinator = msg[1] == 0xFF;
-        if !is_coordinator {
-            if self.next_node.is_none() {
-                self.next_node = Some(next_node);
+    fn seen(&mut self) {
+        self.last_seen_gap = self.last_seen.0.elapsed();
+        self.last_seen = Instant::now().into();
+        if let Some(health) = self.liveness.on_seen() {
+            warn!("{:>14}>ESP Back after {:?} {:?}", self.addr, self.last_seen_gap, health);
+        }
+    }
+
+    fn rssi(&self) -> Option<u8> {
+        self.route.link().and_then(LinkStats::rssi)
+    }
+
+    fn snr(&self) -> Option<u8> {
+        self.route.link().and_then(LinkStats::snr)
+    }
+
+    fn on_next_node(&mut self, next_node: MacAddr) {
+        let now = Instant::now();
+        if let RouteEvent::Changed { from } = self.route.on_parent(next_node.0, now) {
+            warn!("{:>14}>ESP Changed Next Node: {} -> {}", self.addr, MacAddr(from), next_node);
+            self.db.route_change(&self.addr.0, &from, &next_node.0);
+        }
+        match self.route.check_flapping(now) {
+            Some(true) => error!(
+                "{:>14}>ESP Route flapping: {} next node changes in {} min",
+                self.addr,
+                self.route.recent_changes(now),
+                self.route.flap_window()
+            ),
+            Some(false) => info!("{:>14}>ESP Route stable again", self.addr),
+            None => (),
+        }
+        self.next_node = Some(next_node);
+    }
+
+    fn log_route(&self) {
+        let now = Instant::now();
+        let Some(parent) = &self.next_node else {
+            return;
+        };
+        info!(
+            "{:>14}>ESP Route -> {} changes:{}{}",
+            self.addr,
+            parent,
+            self.route.changes().count(),
+            if self.route.is_flapping() { " FLAPPING" } else { "" }
+        );
+        let signal_args = self.route.signal_args();
+        for (mac, link) in self.route.links() {
+            info!(
+                "{:>14}>ESP Route   via {:>14} time:{:>8}s samples:{}",
+                self.addr,
+                MacAddr(*mac),
+                self.route.time_on(mac, now).as_secs(),
+                link.rssi.count()
+            );
+            for (metric, stats) in [("RSSI", &link.rssi), ("SNR", &link.snr)] {
+                info!(
+                    "{:>14}>ESP Route     {:<4} {} hist:{}",
+                    self.addr,
+                    metric,
+                    stats.describe(signal_args, now),
+                    stats.histogram()
+                );
             }
-            else if &next_node != self.next_node.as_ref().unwrap() {
-                self.next_node = Some(next_node);
-                warn!("{:>14}>ESP Changed Next Node: {}", self.addr, self.next_node.as_ref().unwrap());
-                self.rssi_cnt = 0;
-                self.rssi = 0;
-                self.snr = 0;
+        }
+        for change in self.route.changes() {
+            info!(
+                "{:>14}>ESP Route   {:>8}s ago {} -> {}",
+                self.addr,
+                now.saturating_duration_since(change.at).as_secs(),
+                MacAddr(change.from),
+                MacAddr(change.to)
+            );
+        }
+    }
+
+    /// track the push id, false when the push was seen before
+    fn on_push_id(&mut self, push_id: u16) -> bool {
+        let event = self.push_seq.on_seq(push_id);
+        match event {
+            SeqEvent::Gap(lost) => warn!("{:>14}>ESP PUSH:{:04x} lost:{}", self.addr, push_id, lost),
+            SeqEvent::Reset { from } => error!(
+                "{:>14}>ESP Rebooted, PUSH {:04x} -> {:04x}",
+                self.addr, from, push_id
+            ),
+            SeqEvent::Duplicate | SeqEvent::OutOfOrder => {
+                debug!("{:>14}>ESP PUSH:{:04x} {:?}", self.addr, push_id, event)
             }
+            SeqEvent::First | SeqEvent::InOrder => (),
         }
+        if event.is_new() {
+            self.last_push_id = push_id;
+        }
+        event.is_new()
+    }
 
+    /// the sample for the time series, None when the NETSTAT was seen before
+    fn decode_netstat(&mut self, msg: &[u8], relay_window: Duration) -> Option<NetstatSample> {
         let net_stat_ts = u16::from_be_bytes((&msg[11..STAT_SIZE]).try_into().unwrap());
-        if net_stat_ts > self.net_stat_ts && self.net_stat_ts > 0 {
-            let ts_gap = net_stat_ts - self.net_stat_ts - 1;
-            if ts_gap > 3 {
+        let event = self.netstat_seq.on_seq(net_stat_ts);
+        match event {
+            SeqEvent::Gap(ts_gap) if ts_gap > 3 => error!(
+                "{:>14}>ESP Net Stat Timestamp skipped:{} gap:{:?}",
+                self.addr, ts_gap, self.last_seen_gap
+            ),
+            SeqEvent::Gap(ts_gap) => warn!(
+                "{:>14}>ESP Net Stat Timestamp skipped:{} gap:{:?}",
+                self.addr, ts_gap, self.last_seen_gap
+            ),
+            SeqEvent::Reset { from } => {
                 error!(
-                    "{:>14}>ESP Net Stat Timestamp skipped:{} gap:{:?}",
-                    self.addr,
-                    ts_gap, 
-                    self.last_seen_gap
-                ); 
-            } else if ts_gap > 0 {
-                warn!(
-                    "{:>14}>ESP Net Stat Timestamp skipped:{} gap:{:?}",
-                    self.addr,
-                    ts_gap, 
-                    self.last_seen_gap
-                ); 
-            } 
+                    "{:>14}>ESP Rebooted, Net Stat TS {:04X} -> {:04X}",
+                    self.addr, from, net_stat_ts
+                );
+                self.up_since = Instant::now().into();
+            }
+            event @ (SeqEvent::Duplicate | SeqEvent::OutOfOrder) => {
+                // counters are deltas, adding them twice would skew the totals
+                warn!("{:>14}>ESP Net Stat TS:{:04X} {:?}, ignored", self.addr, net_stat_ts, event);
+                return None;
+            }
+            SeqEvent::First | SeqEvent::InOrder => (),
         }
         self.net_stat_ts = net_stat_ts;
+        let periods = match event {
+            SeqEvent::Gap(lost) => lost.saturating_add(1),
+            _ => 1,
+        };
+        self.liveness.on_netstat(Instant::now(), periods);
+
+        let next_node = MacAddr::from(msg);
+        // a coordinator has no link, its SNR byte is 0xFF and its counters are network wide
+        let is_coordinator = msg[1] == 0xFF;
+        if is_coordinator && self.role != Role::Coordinator {
+            info!("{:>14}>ESP Role {} -> {}", self.addr, self.role, Role::Coordinator);
+            self.role = Role::Coordinator;
+        } else if !is_coordinator && self.role == Role::Coordinator {
+            // router or leaf is decided by the tester, which knows the routes of the others
+            warn!("{:>14}>ESP Role {} -> {}", self.addr, self.role, Role::Leaf);
+            self.role = Role::Leaf;
+        }
+        if !is_coordinator {
+            self.on_next_node(next_node);
+        }
+        let sample = NetstatSample::new(
+            hex::encode(self.addr.0),
+            self.addr.to_string(),
+            &msg[..11],
+            net_stat_ts,
+            self.last_push_id,
+            self.next_node.as_ref().map(MacAddr::to_string).unwrap_or_default(),
+        );
 
-        self.total_rx_ntfy += msg[6] as u32;
-        self.total_rx_bcast += msg[7] as u32;
-        self.total_rx_direct += msg[8] as u32;
-        self.total_relay_req += msg[9] as u32;
-        self.total_relay_ntfy += msg[10] as u32;
         if is_coordinator {
+            self.coordinator.on_netstat(msg);
             info!(
-                "{:>14}>ESP Net Stat TS:{:04x} PUSH:{:04x} NFY:{:6}/{:<6} RXB:{:5} RXD:{:5}",
+                "{:>14}>ESP Net Stat TS:{:04x} PUSH:{:04x} NFY:{:6}/{:<6} RXB:{:5} RXD:{:5} HEARD:{}",
                 self.addr, self.net_stat_ts, 
                 self.last_push_id,
-                    self.total_rx_ntfy, self.total_relay_ntfy,
-                self.total_rx_bcast, self.total_rx_direct,
+                    self.coordinator.rx_ntfy, self.coordinator.relay_ntfy,
+                self.coordinator.rx_bcast, self.coordinator.rx_direct,
+                self.coordinator.heard.len(),
             );
         } else {
+            self.total_rx_ntfy += msg[6] as u32;
+            self.total_rx_bcast += msg[7] as u32;
+            self.total_rx_direct += msg[8] as u32;
+            self.total_relay_req += msg[9] as u32;
+            self.total_relay_ntfy += msg[10] as u32;
+            self.relay.on_netstat(Instant::now(), msg[9] as u32 + msg[10] as u32, msg[3] as u32, relay_window);
             if msg[0] > 0 {
-                self.rssi_cnt += 1;
-                self.rssi += msg[0] as u32;
-                self.snr += msg[1] as u32;
+                for alert in self.route.on_quality(msg[0], msg[1], Instant::now()) {
+                    if alert.degraded {
+                        error!(
+                            "{:>14}>ESP {} degraded to {}: EMA:{:.1} baseline:{:.1}",
+                            self.addr, alert.metric, self.next_node.as_ref().unwrap(), alert.ema, alert.baseline
+                        );
+                    } else {
+                        warn!(
+                            "{:>14}>ESP {} recovered to {}: EMA:{:.1} baseline:{:.1}",
+                            self.addr, alert.metric, self.next_node.as_ref().unwrap(), alert.ema, alert.baseline
+                        );
+                    }
+                }
             }
             self.total_resent += msg[2] as u32;
             self.total_failed_queued += msg[3] as u32;
@@ -188,8 +382,8 @@ impl EspDevice {
                     "{:>14}>ESP Net Stat TS:{:04X} PUSH:{:04x} NFY:{:6}/{:<6} RSSI:{:3} SNR:{:3} FAILQ:{:3} FAIL:{:3} SENT:{:5} RXB:{:5} RXD:{:5} RLY:{:5} -> {}",
                     self.addr, self.net_stat_ts, self.last_push_id,
                     self.total_rx_ntfy, self.total_relay_ntfy, 
-                    self.rssi.checked_div(self.rssi_cnt).unwrap_or_default(), 
-                    self.snr.checked_div(self.rssi_cnt).unwrap_or_default(),
+                    self.rssi().unwrap_or_default(), 
+                    self.snr().unwrap_or_default(),
                     self.total_failed_queued,self.total_failed,
                     self.total_sent,  self.total_rx_bcast, self.total_rx_direct,
                     self.total_relay_req, 
@@ -200,8 +394,8 @@ impl EspDevice {
                     "{:>14}>ESP Net Stat TS:{:04X} PUSH:{:04x} NFY:{:6}/{:<6} RSSI:{:3} SNR:{:3} FAILQ:{:3} FAIL:{:3} SENT:{:5} RXB:{:5} RXD:{:5} RLY:{:5} -> {}",
                     self.addr, self.net_stat_ts, self.last_push_id,
                     self.total_rx_ntfy, self.total_relay_ntfy, 
-                    self.rssi.checked_div(self.rssi_cnt).unwrap_or_default(), 
-                    self.snr.checked_div(self.rssi_cnt).unwrap_or_default(),
+                    self.rssi().unwrap_or_default(), 
+                    self.snr().unwrap_or_default(),
                     self.total_failed_queued,self.total_failed,
                     self.total_sent,  self.total_rx_bcast, self.total_rx_direct,
                     self.total_relay_req, 
@@ -209,15 +403,420 @@ impl EspDevice {
                 );
             }
         }
+        Some(sample)
     }
 }
 
 #[derive(Default)]
 pub(crate) struct EspTester {
     esp_devices: HashMap<MacAddr, EspDevice>,
+    route_args: RouteArgs,
+    watchdog_args: WatchdogArgs,
+    relay_args: RelayArgs,
+    // next nodes with a reported partition behind them
+    partitions: HashSet<MacAddr>,
+    series: Option<SeriesWriter>,
+    db: DbWriter,
+    // the device on the serial port
+    local: Option<MacAddr>,
+    neighbours: NeighbourTable,
+    calls: CallTracker,
+    confirm: CommandVerifier,
 }
 
 impl EspTester {
+    pub fn new(
+        route_args: RouteArgs,
+        watchdog_args: WatchdogArgs,
+        relay_args: RelayArgs,
+        confirm_args: ConfirmArgs,
+    ) -> Self {
+        Self {
+            route_args,
+            watchdog_args,
+            relay_args,
+            confirm: CommandVerifier::new(confirm_args),
+            ..Default::default()
+        }
+    }
+
+    /// append every new NETSTAT to `series`
+    pub fn set_series(&mut self, series: SeriesWriter) {
+        self.series = Some(series);
+    }
+
+    /// record devices, NETSTATs, pushes and route changes in `db`
+    pub fn set_db(&mut self, db: DbWriter) {
+        self.db = db;
+    }
+
+    /// the coordinator at the end of the route of `mac`
+    fn root(&self, mac: &MacAddr) -> Option<MacAddr> {
+        let mut cur = self.esp_devices.get(mac)?;
+        // a routing loop never reaches a coordinator
+        for _ in 0..self.esp_devices.len() {
+            if cur.role == Role::Coordinator {
+                return Some(cur.addr.clone());
+            }
+            cur = self.esp_devices.get(cur.next_node.as_ref()?)?;
+        }
+        None
+    }
+
+    /// after a NETSTAT of `mac`, re-evaluate router/leaf and credit its coordinator
+    fn on_netstat(&mut self, mac: &MacAddr) {
+        let next_nodes: HashSet<MacAddr> = self
+            .esp_devices
+            .values()
+            .filter(|dev| dev.role != Role::Coordinator)
+            .filter_map(|dev| dev.next_node.clone())
+            .collect();
+        for dev in self.esp_devices.values_mut() {
+            let role = Role::classify(
+                dev.role == Role::Coordinator,
+                next_nodes.contains(&dev.addr),
+                dev.total_relay_req + dev.total_relay_ntfy > 0,
+            );
+            if role != dev.role {
+                info!("{:>14}>ESP Role {} -> {}", dev.addr, dev.role, role);
+                dev.role = role;
+            }
+        }
+        let Some(root) = self.root(mac) else {
+            return;
+        };
+        if root == *mac {
+            return;
+        }
+        // a device re-parented to the other mesh is only heard by its new coordinator
+        for other in self.esp_devices.values_mut().filter(|dev| dev.role == Role::Coordinator && dev.addr != root) {
+            if other.coordinator.on_moved(&mac.0) {
+                info!("{:>14}>ESP Coordinator lost {} ({} devices)", other.addr, mac, other.coordinator.heard.len());
+            }
+        }
+        let coordinator = self.esp_devices.get_mut(&root).unwrap();
+        if coordinator.coordinator.on_heard(mac.0) {
+            info!(
+                "{:>14}>ESP Coordinator heard {} ({} devices)",
+                root,
+                mac,
+                coordinator.coordinator.heard.len()
+            );
+        }
+    }
+
+    fn record(&mut self, mac: &MacAddr, sample: Option<NetstatSample>) {
+        let Some(sample) = sample else {
+            return;
+        };
+        self.on_netstat(mac);
+        if self.local.is_none() && self.esp_devices[mac].role == Role::Coordinator {
+            info!("{:>14}>ESP On the serial port", mac);
+            self.local = Some(mac.clone());
+        }
+        self.db.netstat(&sample);
+        if let Some(series) = &mut self.series {
+            if let Err(e) = series.write(&sample) {
+                error!("cannot write NETSTAT sample: {e}");
+            }
+        }
+    }
+
+    /// report devices missing their NETSTAT periods and devices going silent together
+    pub fn check_liveness(&mut self) {
+        let args = self.watchdog_args;
+        for esp_device in self.esp_devices.values_mut() {
+            let silent = esp_device.last_seen.0.elapsed();
+            match esp_device.liveness.check(silent, &args) {
+                Some(Health::Stale) => warn!(
+                    "{:>14}>ESP Stale, silent for {:?} NETSTAT period {:?}",
+                    esp_device.addr,
+                    silent,
+                    esp_device.liveness.period(&args).unwrap_or_default()
+                ),
+                Some(Health::Offline) => error!("{:>14}>ESP Offline, silent for {:?}", esp_device.addr, silent),
+                _ => (),
+            }
+        }
+
+        // next node -> devices behind it, silent ones
+        let mut behind: HashMap<&MacAddr, (usize, Vec<String>)> = HashMap::new();
+        for esp_device in self.esp_devices.values() {
+            if let Some(next_node) = &esp_device.next_node {
+                let (total, silent) = behind.entry(next_node).or_default();
+                *total += 1;
+                if esp_device.liveness.health() != Health::Online {
+                    silent.push(esp_device.addr.to_string());
+                }
+            }
+        }
+        let mut partitions = HashSet::new();
+        for (next_node, (total, silent)) in behind {
+            if silent.len() < args.partition_size {
+                continue;
+            }
+            if !self.partitions.contains(next_node) {
+                error!(
+                    "{:>14}>ESP Partition, {} of {} devices behind it silent: {}",
+                    next_node,
+                    silent.len(),
+                    total,
+                    silent.join(", ")
+                );
+            }
+            partitions.insert(next_node.clone());
+        }
+        for next_node in self.partitions.difference(&partitions) {
+            warn!("{:>14}>ESP Partition cleared", next_node);
+        }
+        self.partitions = partitions;
+    }
+
+    /// one line per device after a header line, grouped by coordinator with the coordinator
+    /// first, then one line per coordinator with its network wide counters
+    pub fn summary(&self) -> Vec<String> {
+        let nodes: HashMap<String, Node> = self
+            .topology(Duration::MAX)
+            .nodes
+            .into_iter()
+            .map(|node| (node.mac.clone(), node))
+            .collect();
+        let node = |dev: &EspDevice| nodes.get(&hex::encode(dev.addr.0));
+        let mut devices: Vec<&EspDevice> = self.esp_devices.values().collect();
+        devices.sort_by_cached_key(|dev| {
+            let root = node(dev).and_then(|n| n.root.clone());
+            (root.is_none(), root, dev.role, dev.addr.to_string())
+        });
+        let mut lines = vec![format!(
+            "{:>14} {:>11} {:>8} {:>14} {:>4} {:>4} {:>4} {:>6} {:>5} {:>5} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>9}",
+            "DEVICE", "ROLE", "KIND", "NEXT NODE", "HOPS", "RSSI", "SNR", "SENT", "FAIL", "FAILQ", "RESENT",
+            "RXB", "RXD", "NFY", "RLYREQ", "RLYNFY", "LOSS%", "UPTIME"
+        )];
+        for dev in &devices {
+            let kind = match registry::get(&dev.addr.0) {
+                Some(info) if !info.role.is_empty() => info.role,
+                _ => "-".to_string(),
+            };
+            let opt = |v: Option<i32>| v.map_or("-".to_string(), |v| v.to_string());
+            lines.push(format!(
+                "{:>14} {:>11} {:>8} {:>14} {:>4} {:>4} {:>4} {:>6} {:>5} {:>5} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6.1} {:>8}s",
+                dev.addr.to_string(),
+                dev.role,
+                kind,
+                dev.next_node.as_ref().map_or("-".to_string(), MacAddr::to_string),
+                opt(node(dev).and_then(|n| n.hops).map(|h| h as i32)),
+                opt(dev.rssi().map(i32::from)),
+                opt(dev.snr().map(i32::from)),
+                dev.total_sent,
+                dev.total_failed,
+                dev.total_failed_queued,
+                dev.total_resent,
+                dev.total_rx_bcast,
+                dev.total_rx_direct,
+                dev.total_rx_ntfy,
+                dev.total_relay_req,
+                dev.total_relay_ntfy,
+                dev.netstat_seq.loss(),
+                dev.up_since.0.elapsed().as_secs(),
+            ));
+        }
+        for dev in devices.iter().filter(|dev| dev.role == Role::Coordinator) {
+            let stats = &dev.coordinator;
+            lines.push(format!(
+                "{:>14} coordinator heard:{} NFY:{} RXB:{} RXD:{} RLYREQ:{} RLYNFY:{}",
+                dev.addr.to_string(),
+                stats.heard.len(),
+                stats.rx_ntfy,
+                stats.rx_bcast,
+                stats.rx_direct,
+                stats.relay_req,
+                stats.relay_ntfy
+            ));
+        }
+        lines
+    }
+
+    /// per device counters for the Prometheus exporter
+    pub fn metrics(&self, m: &mut Metrics) {
+        for dev in self.esp_devices.values() {
+            let mac = hex::encode(dev.addr.0);
+            let name = dev.addr.to_string();
+            let labels = [("mac", mac.as_str()), ("name", name.as_str())];
+            let counters = [
+                ("esp_sent_total", "frames sent by the device", dev.total_sent),
+                ("esp_failed_total", "frames the device failed to send", dev.total_failed),
+                ("esp_failed_queued_total", "frames the device failed to queue", dev.total_failed_queued),
+                ("esp_resent_total", "frames resent by the device", dev.total_resent),
+                ("esp_rx_bcast_total", "broadcasts received", dev.total_rx_bcast),
+                ("esp_rx_direct_total", "direct frames received", dev.total_rx_direct),
+                ("esp_rx_notify_total", "notifies received", dev.total_rx_ntfy),
+                ("esp_relay_req_total", "requests relayed", dev.total_relay_req),
+                ("esp_relay_notify_total", "notifies relayed", dev.total_relay_ntfy),
+            ];
+            for (metric, help, value) in counters {
+                m.counter(metric, help, &labels, value as f64);
+            }
+            m.counter("esp_reboots_total", "NETSTAT counter resets", &labels, dev.netstat_seq.resets() as f64);
+            m.gauge("esp_netstat_loss_ratio", "lost NETSTATs / expected", &labels, dev.netstat_seq.loss() / 100.0);
+            m.gauge("esp_push_loss_ratio", "lost pushes / expected", &labels, dev.push_seq.loss() / 100.0);
+            if let (Some(rssi), Some(snr)) = (dev.rssi(), dev.snr()) {
+                m.gauge("esp_rssi", "average RSSI to the next node", &labels, rssi as f64);
+                m.gauge("esp_snr", "average SNR to the next node", &labels, snr as f64);
+            }
+            if let Some(link) = dev.route.link().filter(|link| link.rssi.count() > 0) {
+                m.gauge("esp_rssi_ema", "moving average RSSI to the next node", &labels, link.rssi.ema());
+                m.gauge("esp_snr_ema", "moving average SNR to the next node", &labels, link.snr.ema());
+            }
+            if let Some(rate) = dev.relay.relay_rate() {
+                m.gauge("esp_relay_per_minute", "frames relayed per minute over the relay window", &labels, rate);
+            }
+            if dev.role == Role::Coordinator {
+                let stats = &dev.coordinator;
+                let counters = [
+                    ("esp_coordinator_rx_notify_total", "notifies received network wide", stats.rx_ntfy),
+                    ("esp_coordinator_rx_bcast_total", "broadcasts received network wide", stats.rx_bcast),
+                    ("esp_coordinator_rx_direct_total", "direct frames received network wide", stats.rx_direct),
+                    ("esp_coordinator_relay_req_total", "requests relayed network wide", stats.relay_req),
+                    ("esp_coordinator_relay_notify_total", "notifies relayed network wide", stats.relay_ntfy),
+                ];
+                for (metric, help, value) in counters {
+                    m.counter(metric, help, &labels, value as f64);
+                }
+                m.gauge("esp_coordinator_devices", "devices heard through the coordinator", &labels, stats.heard.len() as f64);
+            }
+            m.gauge("esp_last_seen_seconds", "seconds since the last push", &labels, dev.last_seen.0.elapsed().as_secs_f64());
+            m.gauge("esp_uptime_seconds", "seconds since first seen or last reboot", &labels, dev.up_since.0.elapsed().as_secs_f64());
+            m.gauge("esp_online", "1 unless stale or offline", &labels, (dev.liveness.health() == Health::Online) as u8 as f64);
+        }
+    }
+
+    /// NETSTAT and push id loss of every device
+    pub fn log_loss(&self) {
+        for esp_device in self.esp_devices.values() {
+            info!("{:>14}>ESP Loss NETSTAT {}", esp_device.addr, esp_device.netstat_seq);
+            info!("{:>14}>ESP Loss PUSH    {}", esp_device.addr, esp_device.push_seq);
+        }
+    }
+
+    /// next node history and per link quality of every device
+    pub fn log_routes(&self) {
+        for esp_device in self.esp_devices.values() {
+            esp_device.log_route();
+        }
+    }
+
+    /// routers by relayed traffic, their share and whether their queue failures grow
+    pub fn log_relay(&self) {
+        let downstream = self.topology(Duration::MAX).downstream();
+        let rows = self
+            .esp_devices
+            .values()
+            .filter_map(|dev| {
+                Some(RelayRow {
+                    name: dev.addr.to_string(),
+                    downstream: downstream.get(&hex::encode(dev.addr.0)).copied().unwrap_or_default(),
+                    relay_rate: dev.relay.relay_rate()?,
+                    failed_queued_rate: dev.relay.failed_queued_rate()?,
+                    rising: dev.relay.failed_queued_rising(),
+                })
+            })
+            .collect();
+        for line in relay::report(rows, &self.relay_args) {
+            info!("RELAY: {line}");
+        }
+    }
+
+    /// payload of the NOTIFY asking every device for its neighbour table
+    pub fn neighbour_query(&mut self) -> Vec<u8> {
+        self.neighbours.start_query(Instant::now());
+        neighbours::query()
+    }
+
+    pub fn neighbours(&self) -> &NeighbourTable {
+        &self.neighbours
+    }
+
+    /// neighbour matrix and the devices that did not answer the last query
+    pub fn log_neighbours(&self) {
+        for line in self.neighbours.matrix() {
+            info!("NEIGHBOURS: {line}");
+        }
+        if self.neighbours.query_at().is_some() {
+            for mac in self.neighbours.missing(self.esp_devices.keys().map(|mac| &mac.0)) {
+                warn!("{:>14}>ESP No neighbour update", MacAddr(mac));
+            }
+        }
+    }
+
+    /// the device on the serial port, by default the first coordinator sending a NETSTAT
+    pub fn set_local_device(&mut self, mac: Mac) {
+        self.local = Some(MacAddr(mac));
+    }
+
+    /// name of the device on the serial port, `local` until it is known
+    pub fn local_device(&self) -> String {
+        self.local.as_ref().map_or_else(|| "local".to_string(), MacAddr::to_string)
+    }
+
+    pub fn calls(&self) -> &CallTracker {
+        &self.calls
+    }
+
+    /// a payload sent to the mesh, pin commands are call events waiting for confirmation
+    pub fn on_command(&mut self, payload: &[u8]) {
+        if let Some(command) = PinCommand::parse(payload) {
+            self.calls.on_command(&command, call::unix_now());
+            self.confirm.on_command(command, Instant::now());
+        }
+    }
+
+    fn log_outcomes(outcomes: Vec<Outcome>, timeout: Duration) {
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Confirmed(cmd, latency) => info!("{:>14}>ESP GPIO {} confirmed after {:?}", MacAddr(cmd.mac), cmd, latency),
+                Outcome::Mismatch(cmd, level, latency) => error!("{:>14}>ESP GPIO {} reported level {} after {:?}", MacAddr(cmd.mac), cmd, level, latency),
+                Outcome::NotConfirmed(cmd) => error!("{:>14}>ESP GPIO {} not confirmed within {:?}", MacAddr(cmd.mac), cmd, timeout),
+            }
+        }
+    }
+
+    /// `pin, level` pairs pushed by `mac`
+    fn on_gpio(&mut self, mac: &[u8; 6], data: &[u8]) {
+        self.calls.on_gpio(mac, data, call::unix_now());
+        let outcomes = self.confirm.on_gpio(mac, data, Instant::now());
+        Self::log_outcomes(outcomes, self.confirm.timeout());
+    }
+
+    /// pin commands that were not confirmed in time
+    pub fn check_confirmations(&mut self) {
+        let outcomes = self.confirm.expire(Instant::now());
+        Self::log_outcomes(outcomes, self.confirm.timeout());
+    }
+
+    pub fn log_confirm(&self) {
+        self.confirm.log_report();
+    }
+
+    pub fn log_calls(&self) {
+        for line in self.calls.report() {
+            info!("CALLS: {line}");
+        }
+    }
+
+    pub fn topology(&self, silent_after: Duration) -> Topology {
+        Topology::build(
+            self.esp_devices.values().map(|dev| NodeInput {
+                mac: dev.addr.0,
+                coordinator: dev.role == Role::Coordinator,
+                next_hop: dev.next_node.as_ref().map(|mac| mac.0),
+                rssi: dev.rssi(),
+                snr: dev.snr(),
+                last_seen: dev.last_seen.0.elapsed(),
+            }),
+            silent_after,
+        )
+    }
+
     pub fn trace_esp_data(&mut self, msg_type: u8, data: &[u8]) {
         match msg_type {
             MSG_TYPE_PUSH_NETSTAT => self.decode_push_netstat(data),
@@ -227,52 +826,69 @@ impl EspTester {
         };
     }
 
-    fn decode_push(&mut self, data: &[u8]) -> &mut EspDevice {
+    /// the sending device, None when the push is a duplicate
+    fn decode_push(&mut self, data: &[u8]) -> Option<&mut EspDevice> {
         let esp_device = self
             .esp_devices
             .entry(MacAddr::from(data))
-            .or_insert(EspDevice::new(MacAddr::from(data)));
-        esp_device.last_seen_gap = esp_device.last_seen.0.elapsed();
-        esp_device.last_seen = Instant::now().into();
-        esp_device.last_push_id = u16::from_be_bytes(
+            .or_insert_with(|| EspDevice::new(MacAddr::from(data), &self.route_args, &self.db));
+        esp_device.seen();
+        let push_id = u16::from_be_bytes(
             (&data[(data.len() - 8)..(data.len() - 6)])
                 .try_into()
                 .unwrap(),
         );
-        esp_device
+        esp_device.on_push_id(push_id).then_some(esp_device)
     }
 
     fn decode_push_netstat(&mut self, data: &[u8]) {
-        let esp_device = self.decode_push(data);
-        esp_device.decode_netstat(&data[..(STAT_SIZE + 6)]);
+        let relay_window = self.relay_args.window();
+        if let Some(esp_device) = self.decode_push(data) {
+            let sample = esp_device.decode_netstat(&data[..(STAT_SIZE + 6)], relay_window);
+            let mac = esp_device.addr.clone();
+            self.record(&mac, sample);
+        }
     }
 
     fn decode_push_gpio(&mut self, data: &[u8]) {
-        let _esp_device = self.decode_push(data);
+        if let Some(esp_device) = self.decode_push(data) {
+            let (mac, push_id) = (esp_device.addr.0, esp_device.last_push_id);
+            self.db.gpio(&mac, push_id, &data[..(data.len() - 8)]);
+            self.on_gpio(&mac, &data[..(data.len() - 8)]);
+        }
     }
 
     fn decode_notify(&mut self, data: &[u8]) {
         let mac = MacAddr::from(&data[1..7]);
         if data[0] & MSG_TYPE_PUSH != 0 {
             let esp_device = self.esp_devices
-                .entry(mac)
-                .or_insert(EspDevice::new(MacAddr::from(&data[1..7])));
-            esp_device.last_seen_gap = esp_device.last_seen.0.elapsed();
-            esp_device.last_seen = Instant::now().into();
+                .entry(mac.clone())
+                .or_insert_with(|| EspDevice::new(MacAddr::from(&data[1..7]), &self.route_args, &self.db));
+            esp_device.seen();
             let push_id = u16::from_be_bytes(
                 (&data[(data.len() - 2)..])
                     .try_into()
                     .unwrap(),
             );
-            if esp_device.last_push_id < push_id // newer
-            || esp_device.last_push_id - push_id > 100 // or re-cycled push id
-            {
-                esp_device.last_push_id = push_id;
-                match data[0] {
-                    MSG_TYPE_PUSH_NETSTAT => esp_device.decode_netstat(&data[7..(7 + STAT_SIZE + 6)]),
-                    MSG_TYPE_PUSH_GPIO => (),
-                    _ => (),
+            if esp_device.on_push_id(push_id) {
+                let sample = match data[0] {
+                    MSG_TYPE_PUSH_NETSTAT => esp_device.decode_netstat(&data[7..(7 + STAT_SIZE + 6)], self.relay_args.window()),
+                    MSG_TYPE_PUSH_GPIO => {
+                        self.db.gpio(&esp_device.addr.0, push_id, &data[7..(data.len() - 2)]);
+                        self.on_gpio(&mac.0, &data[7..(data.len() - 2)]);
+                        None
+                    }
+                    _ => None,
+                };
+                self.record(&mac, sample);
+            }
+        } else if data[0] == NOTIFY_NEIGH_UPDATE {
+            match neighbours::parse_update(data) {
+                Some((reporter, neighbours)) => {
+                    debug!("{:>14}>ESP Neighbour update, {} neighbours", mac, neighbours.len());
+                    self.neighbours.on_update(reporter, neighbours);
                 }
+                None => warn!("{:>14}>ESP Bad neighbour update {}", mac, hex::encode(data)),
             }
         } else {
             warn!("{:>14}>ESP NFY{:02X} non-PUSH ", mac, data[0]);
@@ -294,14 +910,88 @@ mod test {
         println!("esp_devices: {:#?}",esp_tester.esp_devices);
     }
 
+    #[test]
+    fn test_summary() {
+        let data = hex::decode("c92300000002010001010106416867254eed8406457cdfa1dee03c").unwrap();
+        let mut esp_tester = EspTester::default();
+        esp_tester.trace_esp_data(MSG_TYPE_PUSH_NETSTAT, &pop_all_escaped(&data));
+        let summary = esp_tester.summary();
+        assert_eq!(summary.len(), 2);
+        assert!(summary[0].trim_start().starts_with("DEVICE"));
+        assert!(summary[1].trim_start().starts_with("7cdfa1dee03c"));
+    }
+
+    #[test]
+    fn test_push_response() {
+        let data = hex::decode("c92300000002010001010106416867254eed8406457cdfa1dee03c").unwrap();
+        assert_eq!(
+            push_response(MSG_TYPE_PUSH_NETSTAT, &data).map(hex::encode),
+            Some("610006457cdfa1dee03c".to_string())
+        );
+        assert_eq!(push_response(MSG_TYPE_NOTIFY, &data), None);
+        // the same push forwarded by the coordinator
+        let data = hex::decode("416867254e3ff0ed47000000000c0000000c0001a0764ead1d3000170b04").unwrap();
+        assert_eq!(
+            push_response(MSG_TYPE_NOTIFY, &data).map(hex::encode),
+            Some("61000b046867254e3ff0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_roles_two_coordinators() {
+        let netstat = |id: u8, next: u8, snr: u8| {
+            let mut data = vec![0xc9, snr, 0, 0, 0, 2, 1, 0, 1, 0, 0, 0, 1];
+            data.extend([0xa0, 0, 0, 0, 0, next, 0, 1, 0xa0, 0, 0, 0, 0, id]);
+            data
+        };
+        let mut esp_tester = EspTester::default();
+        for data in [netstat(1, 0, 0xFF), netstat(2, 0, 0xFF), netstat(3, 1, 10), netstat(4, 3, 10), netstat(5, 2, 10)] {
+            esp_tester.trace_esp_data(MSG_TYPE_PUSH_NETSTAT, &data);
+        }
+        let dev = |id: u8| &esp_tester.esp_devices[&MacAddr([0xa0, 0, 0, 0, 0, id])];
+        assert_eq!(dev(1).role, Role::Coordinator);
+        assert_eq!(dev(3).role, Role::Router);
+        assert_eq!(dev(4).role, Role::Leaf);
+        assert_eq!(dev(1).coordinator.heard.len(), 2);
+        assert_eq!(dev(2).coordinator.heard.len(), 1);
+        // coordinator counters stay out of the node totals
+        assert_eq!((dev(1).total_rx_ntfy, dev(1).coordinator.rx_ntfy), (0, 1));
+
+        let summary = esp_tester.summary();
+        let order: Vec<&str> = summary[1..6].iter().map(|line| line.split_whitespace().next().unwrap()).collect();
+        assert_eq!(order, ["a00000000001", "a00000000003", "a00000000004", "a00000000002", "a00000000005"]);
+        assert!(summary[6].contains("coordinator heard:2"));
+        assert!(summary[7].contains("coordinator heard:1"));
+        // 4 re-parents from 3 (mesh of 1) to 5 (mesh of 2)
+        let mut moved = netstat(4, 5, 10);
+        // next NETSTAT and push id
+        (moved[12], moved[20]) = (2, 2);
+        esp_tester.trace_esp_data(MSG_TYPE_PUSH_NETSTAT, &moved);
+        let dev = |id: u8| &esp_tester.esp_devices[&MacAddr([0xa0, 0, 0, 0, 0, id])];
+        assert_eq!(dev(1).coordinator.heard.len(), 1);
+        assert_eq!(dev(2).coordinator.heard.len(), 2);
+    }
+
     #[test]
     fn test_notify_push() {
+        let registry = registry::Registry::from_toml(r#"
+            [[device]]
+            mac = "6867254e3ff0"
+            name = "Tester Bed 105"
+            [[device]]
+            mac = "6867254d6258"
+            name = "COORDINATOR"
+            [[device]]
+            mac = "a0764ead1d30"
+            name = "Tester Bed 108"
+        "#).unwrap();
         let data = hex::decode("416867254e3ff0ed47000000000c0000000c0001a0764ead1d3000170b04").unwrap();
         let escaped_data = pop_all_escaped(&data);
         let mut esp_tester = EspTester::default();
         esp_tester.trace_esp_data(MSG_TYPE_NOTIFY, &escaped_data);
         let mac_addr = MacAddr::from(hex::decode("6867254e3ff0").unwrap().as_slice());
         assert!(esp_tester.esp_devices.contains_key(&mac_addr));
-        assert_eq!(format!("{}",esp_tester.esp_devices[&mac_addr].next_node.as_ref().unwrap()),"COORDINATOR");
+        let next_node = esp_tester.esp_devices[&mac_addr].next_node.as_ref().unwrap();
+        assert_eq!(registry.name(&next_node.0), Some("Tester Bed 108"));
     }
 }
\ No newline at end of file
@@ -1,36 +1,47 @@
 #![allow(unused_imports)]
 
 use std::{
-    sync::{
-        atomic::{AtomicU16, Ordering},
-        Arc, Condvar, Mutex, RwLock,
-    },
+    sync::{Arc, Condvar, Mutex},
     thread::{self, sleep},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use log::{debug, error, info, trace, warn};
 use rand_distr::{Distribution, Normal};
 
 use crate::{
-    test_esp::{EspTester, MSG_TYPE_REQ_CONFIG, MSG_TYPE_RES_CONFIG},
-    ConnectArgs,
+    call::CallArgs,
+    db::DbWriter,
+    esp_log::EspLog,
+    fault::FaultInjector,
+    latency::LatencyStats,
+    metrics::{self, Metrics},
+    neighbours::NEIGH_COLLECT,
+    push_ack::PushAcker,
+    registry,
+    retry::RetryPolicy,
+    seq::next_seq,
+    series::SeriesWriter,
+    test_esp::{push_response, EspTester, MSG_TYPE_REQ_CONFIG, MSG_TYPE_RES_CONFIG},
+    topology::{Issue, TopologyArgs},
+    watchdog::WATCHDOG_TICK,
+    window::{InFlight, SendWindow},
+    TestArgs,
 };
 
 // rx_fifo_full_threshold
 const READ_BUF_SIZE: usize = 128;
 // EOT (CTRL-D)
-const AT_CMD: u8 = 0x04;
-const AT_ESC: u8 = 0x1b;
+pub(crate) const AT_CMD: u8 = 0x04;
+pub(crate) const AT_ESC: u8 = 0x1b;
 const AT_ESC_MASK: u8 = 0x30;
 
 // max message size to receive
 // leave some extra space for AT-CMD characters
 const MAX_BUFFER_SIZE: usize = 5 * READ_BUF_SIZE + 20;
 const RESET_BUFFER_SIZE: usize = MAX_BUFFER_SIZE - READ_BUF_SIZE;
-const MAX_RETRIES: u32 = 20;
 
-type UartVec = Vec<u8>;
+pub(crate) type UartVec = Vec<u8>;
 
 #[inline]
 fn pop_escaped(buf: &[u8], offset: &mut usize) -> Option<u8> {
@@ -61,7 +72,7 @@ pub(crate) fn pop_all_escaped(buf: &[u8]) -> Vec<u8> {
     }
     out
 }
-trait PushEscape {
+pub(crate) trait PushEscape {
     fn push_escaped(&mut self, b: u8);
     #[allow(unused)]
     fn pop_escaped(&mut self) -> Option<u8>;
@@ -96,37 +107,302 @@ impl PushEscape for UartVec {
     }
 }
 
-struct WriteData {
-    seq_no: AtomicU16,
-    wbuf: UartVec,
+/// Escaped frame body (seq + payload) and its checksum
+pub(crate) struct EncodedFrame {
+    pub body: UartVec,
+    pub csum: u8,
+    pub payload_len: usize,
 }
-pub fn test(
-    connect_args: ConnectArgs,
-    no_send: bool,
-    load_send: bool,
-    mut at_cmd: bool,
-    send: Vec<String>,
-    send_time: Vec<u64>,
-    esp_test: bool,
-) {
+
+impl EncodedFrame {
+    /// bytes as written to the port: body, escaped checksum and AT_CMD
+    pub fn wire(&self) -> UartVec {
+        let mut wbuf = UartVec::with_capacity(self.body.len() + 3);
+        wbuf.extend_from_slice(&self.body);
+        wbuf.push_escaped(self.csum);
+        wbuf.push(AT_CMD);
+        wbuf
+    }
+}
+
+/// encode `payload` (message type, part and data) as frame `seq_no`
+/// `force_csum` pads the frame with 0x01 bytes until the checksum matches
+pub(crate) fn encode_frame(seq_no: u16, payload: &[u8], force_csum: Option<u8>) -> EncodedFrame {
+    let mut body = UartVec::with_capacity(2 * payload.len() + 4);
+    body.push_escaped(seq_no as u8);
+    body.push_escaped((seq_no >> 8) as u8);
+    let hdr_len = body.len();
+    payload.iter().for_each(|b| body.push_escaped(*b));
+    let mut csum = body.iter().fold(0u8, |csum, b| csum.wrapping_add(*b));
+    if let Some(cs) = force_csum {
+        while csum != cs {
+            body.push_escaped(1);
+            csum = csum.wrapping_add(1);
+        }
+    }
+    EncodedFrame {
+        payload_len: body.len() - hdr_len,
+        body,
+        csum,
+    }
+}
+
+/// a received frame, unescaped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub seq_no: u16,
+    pub msg_type: u8,
+    pub part: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_ack(&self) -> bool {
+        self.msg_type & 0x80 != 0
+    }
+}
+
+/// decode the bytes between two AT_CMDs, None when short or the checksum does not match
+pub(crate) fn decode_frame(raw: &[u8]) -> Option<Frame> {
+    if raw.len() < 5 {
+        return None;
+    }
+    let mut end = raw.len() - 1;
+    let mut recv_csum = raw[end];
+    if raw[end - 1] == AT_ESC && raw[end - 2] != AT_ESC {
+        //un-escape checksum
+        end -= 1;
+        if recv_csum != AT_ESC {
+            recv_csum &= !AT_ESC_MASK;
+        }
+    }
+    let csum = raw[..end].iter().fold(0u8, |csum, b| csum.wrapping_add(*b));
+    if csum != recv_csum {
+        return None;
+    }
+    let body = pop_all_escaped(&raw[..end]);
+    if body.len() < 4 {
+        return None;
+    }
+    Some(Frame {
+        seq_no: u16::from_le_bytes([body[0], body[1]]),
+        msg_type: body[2],
+        part: body[3],
+        data: body[4..].to_vec(),
+    })
+}
+
+/// sender and receiver totals
+#[derive(Debug, Default)]
+pub(crate) struct SerialCounters {
+    sent: usize,
+    sent_bytes: usize,
+    nack: usize,
+    received: usize,
+    csum_errors: usize,
+    short_frames: usize,
+    // partial frames dropped when the read buffer was reset
+    overruns: usize,
+    stray_acks: usize,
+}
+
+impl SerialCounters {
+    fn metrics(&self, m: &mut Metrics) {
+        let counters = [
+            ("serial_sent_frames_total", "frames sent", self.sent),
+            ("serial_sent_bytes_total", "bytes sent", self.sent_bytes),
+            ("serial_nack_total", "frames abandoned without ACK", self.nack),
+            ("serial_received_frames_total", "frames received with a valid checksum", self.received),
+            ("serial_checksum_errors_total", "frames received with a bad checksum", self.csum_errors),
+            ("serial_short_frames_total", "frames too short for a header", self.short_frames),
+            ("serial_overruns_total", "partial frames dropped on read buffer reset", self.overruns),
+            ("serial_stray_acks_total", "ACKs matching no frame in flight", self.stray_acks),
+        ];
+        for (metric, help, value) in counters {
+            m.counter(metric, help, &[], value as f64);
+        }
+    }
+}
+
+pub fn test(test_args: TestArgs) {
+    let TestArgs {
+        connect_args,
+        no_send,
+        load_send,
+        mut at_cmd,
+        send,
+        send_time,
+        esp_test,
+        window,
+        retry_args,
+        fault_args,
+        push_ack_args,
+        topology_args,
+        route_args,
+        watchdog_args,
+        relay_args,
+        call_args,
+        confirm_args,
+        esp_log_args,
+        summary_every,
+        metrics_args,
+        series_args,
+        db_args,
+    } = test_args;
+    let faults = FaultInjector::new(fault_args).unwrap_or_else(|e| {
+        clap::Error::raw(clap::error::ErrorKind::ValueValidation, format!("--fault: {e}\n")).exit()
+    });
+    let db = match &db_args.db {
+        Some(path) => DbWriter::open(path, &connect_args.port).unwrap_or_else(|e| {
+            error!("cannot open {}: {e}", path.display());
+            DbWriter::default()
+        }),
+        None => DbWriter::default(),
+    };
     let mut serial = serialport::new(connect_args.port, connect_args.baud)
         .open()
         .expect("Failed to open port");
-    let write_data = Arc::new(RwLock::new(WriteData {
-        seq_no: AtomicU16::new(0),
-        wbuf: Vec::with_capacity(MAX_BUFFER_SIZE),
-    }));
+    let send_window = Arc::new(Mutex::new(SendWindow::new(window)));
     if esp_test {
         at_cmd = true;
     }
     let answer_data = Arc::new(Mutex::new(UartVec::with_capacity(MAX_BUFFER_SIZE)));
-    let esp_tester = Arc::new(Mutex::new(EspTester::default()));
+    let mut esp = EspTester::new(route_args, watchdog_args, relay_args, confirm_args);
+    esp.set_db(db.clone());
+    match esp_log_args.device() {
+        Ok(Some(mac)) => esp.set_local_device(mac),
+        Ok(None) => (),
+        Err(e) => error!("--esp-log-device: {e}"),
+    }
+    if let Some(path) = &series_args.samples {
+        match SeriesWriter::open(path, series_args.samples_format) {
+            Ok(series) => esp.set_series(series),
+            Err(e) => error!("cannot open {}: {e}", path.display()),
+        }
+    }
+    let esp_tester = Arc::new(Mutex::new(esp));
     let pair = Arc::new((Mutex::new(false), Condvar::new()));
     let pair2 = Arc::clone(&pair);
+    let pair3 = Arc::clone(&pair);
+    let latency = Arc::new(Mutex::new(LatencyStats::default()));
+    let policy = Arc::new(Mutex::new(RetryPolicy::new(retry_args)));
+    let faults = Arc::new(Mutex::new(faults));
+    let push_acks = Arc::new(Mutex::new(PushAcker::new(push_ack_args)));
+    let counters = Arc::new(Mutex::new(SerialCounters::default()));
+    {
+        let latency = latency.clone();
+        let faults = faults.clone();
+        let push_acks = push_acks.clone();
+        let esp_tester = esp_tester.clone();
+        let counters = counters.clone();
+        let topology_args = topology_args.clone();
+        let call_args = call_args.clone();
+        let db = db.clone();
+        // Ctrl-C and SIGTERM
+        ctrlc::set_handler(move || {
+            latency.lock().unwrap().log_summary();
+            faults.lock().unwrap().log_report();
+            push_acks.lock().unwrap().log_report();
+            log_summary(&counters, esp_test.then_some(&*esp_tester));
+            if esp_test {
+                let esp_tester = esp_tester.lock().unwrap();
+                esp_tester.log_routes();
+                esp_tester.log_loss();
+                esp_tester.log_relay();
+                esp_tester.log_calls();
+                esp_tester.log_confirm();
+            }
+            if esp_test {
+                export_calls(&esp_tester, &call_args);
+            }
+            if esp_test && topology_args.topology.is_some() {
+                export_topology(&esp_tester, &topology_args);
+            }
+            db.close();
+            std::process::exit(0);
+        })
+        .expect("Failed to set Ctrl-C handler");
+    }
+    if let Some(addr) = metrics_args.metrics_listen {
+        let esp_tester = esp_tester.clone();
+        let counters = counters.clone();
+        let send_window = send_window.clone();
+        let latency = latency.clone();
+        let policy = policy.clone();
+        metrics::serve(addr, move || {
+            let mut m = Metrics::default();
+            counters.lock().unwrap().metrics(&mut m);
+            m.gauge("serial_inflight", "frames waiting for their ACK", &[], send_window.lock().unwrap().len() as f64);
+            let latency = latency.lock().unwrap();
+            for (quantile, p) in [("0.5", 50.0), ("0.9", 90.0), ("0.99", 99.0)] {
+                m.gauge(
+                    "serial_rtt_seconds",
+                    "ACK round trip time quantiles",
+                    &[("quantile", quantile)],
+                    latency.all().percentile(p).as_secs_f64(),
+                );
+            }
+            m.gauge("serial_rto_seconds", "current ACK timeout", &[], policy.lock().unwrap().rto().as_secs_f64());
+            esp_tester.lock().unwrap().metrics(&mut m);
+            m
+        });
+    }
+    if summary_every > 0 {
+        let esp_tester = esp_tester.clone();
+        let counters = counters.clone();
+        thread::spawn(move || loop {
+            sleep(Duration::from_secs(60 * summary_every));
+            log_summary(&counters, esp_test.then_some(&*esp_tester));
+        });
+    }
+    if esp_test {
+        let esp_tester = esp_tester.clone();
+        thread::spawn(move || loop {
+            sleep(WATCHDOG_TICK);
+            let mut esp_tester = esp_tester.lock().unwrap();
+            esp_tester.check_liveness();
+            esp_tester.check_confirmations();
+        });
+    }
+    {
+        let esp_tester = esp_tester.clone();
+        let answer_data = answer_data.clone();
+        thread::spawn(move || {
+            for line in std::io::stdin().lines().map_while(Result::ok) {
+                match line.trim() {
+                    "t" | "topology" => export_topology(&esp_tester, &topology_args),
+                    "r" | "routes" => esp_tester.lock().unwrap().log_routes(),
+                    "l" | "loss" => esp_tester.lock().unwrap().log_loss(),
+                    "h" | "hotspots" => esp_tester.lock().unwrap().log_relay(),
+                    "c" | "calls" => {
+                        esp_tester.lock().unwrap().log_calls();
+                        export_calls(&esp_tester, &call_args);
+                    }
+                    "n" | "neighbours" if no_send => {
+                        warn!("NEIGHBOURS: cannot query with --no-send, use the neighbours subcommand")
+                    }
+                    "n" | "neighbours" => {
+                        query_neighbours(&esp_tester, &answer_data, &pair3, &topology_args)
+                    }
+                    "" => (),
+                    cmd => warn!(
+                        "unknown command `{cmd}`, try: topology, routes, loss, hotspots, calls, neighbours"
+                    ),
+                }
+            }
+        });
+    }
 
     if !no_send {
-        let wlock_data = write_data.clone();
+        let wwindow = send_window.clone();
         let alock_data = answer_data.clone();
+        let wlatency = latency.clone();
+        let wpolicy = policy.clone();
+        let wfaults = faults.clone();
+        let wcounters = counters.clone();
+        let wacks = push_acks.clone();
+        let wesp = esp_tester.clone();
+        let wdb = db.clone();
 
         let normal = Normal::new(
             if load_send { 70.0 } else { 500.0 },
@@ -136,115 +412,211 @@ pub fn test(
         let mut wserial = serial
             .try_clone()
             .expect("Failed to clone port for writing");
-        let mut hex_sends_iter = send.into_iter().map(|s| hex::decode(s).unwrap()).cycle();
+        let mut hex_sends_iter = send.into_iter().cycle();
         let mut send_time_iter = send_time.into_iter().cycle();
 
         thread::spawn(move || {
             let (lock, cvar) = &*pair;
 
             let mut seq_no = 0;
-            let mut total_sent: usize = 0;
-            let mut total_sent_bytes: usize = 0;
-            let mut total_nack: usize = 0;
-            let mut retries = 0;
+            let mut next_send_at = Instant::now()
+                + Duration::from_secs(send_time_iter.next().unwrap_or(60));
             loop {
+                let now = Instant::now();
                 if !load_send {
-                    let started = lock.lock().unwrap();
-                    cvar.wait_timeout(started, Duration::from_secs(3)).ok();
-                    let wdata = wlock_data.write().unwrap();
-                    if wdata.seq_no.load(Ordering::Relaxed) > 0 {
-                        send_all(&mut wserial, wdata, at_cmd, load_send);
-                        if retries < MAX_RETRIES {
-                            retries += 1;
-                            warn!("last send was NG. resending #{:02} ...", retries);
-                            continue;
-                        } else {
-                            total_nack += 1;
-                            error!("last send was NG. max retries reached.");
+                    let mut resend = Vec::new();
+                    {
+                        let mut window = wwindow.lock().unwrap();
+                        let policy = wpolicy.lock().unwrap();
+                        for expired_seq in window.expired(now) {
+                            let frame = window.get_mut(expired_seq).unwrap();
+                            if frame.retries < policy.max_retries() {
+                                frame.retries += 1;
+                                frame.deadline = now + policy.timeout(frame.retries);
+                                warn!(
+                                    "send SEQ:{:04X} was NG after {:?}. resending #{:02} ...",
+                                    expired_seq,
+                                    now - frame.sent_at,
+                                    frame.retries
+                                );
+                                resend.push(frame.frame.clone());
+                                continue;
+                            }
+                            let frame = window.remove(expired_seq).unwrap();
+                            wfaults.lock().unwrap().on_abandon(expired_seq);
+                            wdb.frame(frame.seq_no, frame.payload_len, frame.retries, None);
+                            wcounters.lock().unwrap().nack += 1;
+                            error!(
+                                "send SEQ:{:04X} was NG. max retries reached. abandoned retries:{} payload:{}B elapsed:{:?} {}",
+                                frame.seq_no,
+                                frame.retries,
+                                frame.payload_len,
+                                frame.sent_at.elapsed(),
+                                hex::encode(&frame.frame),
+                            );
                         }
                     }
-                    retries = 0;
-                }
-                if !load_send {
-                    let started = lock.lock().unwrap();
-                    cvar.wait_timeout(
-                        started,
-                        Duration::from_secs(send_time_iter.next().unwrap_or(60)),
-                    )
-                    .ok();
-                }
-                let mut wdata = wlock_data.write().unwrap();
-
-                seq_no += 1;
-
-                wdata.wbuf.clear();
-                let b = (seq_no) as u8;
-                wdata.wbuf.push_escaped(b);
-                let b = (seq_no >> 8) as u8;
-                wdata.wbuf.push_escaped(b);
-                let mut adata = alock_data.lock().unwrap();
-                if !adata.is_empty() {
-                    for &b in adata.iter() {
-                        wdata.wbuf.push(b);
+                    for frame in resend {
+                        send_all(&mut wserial, &frame, at_cmd, load_send);
                     }
-                    adata.clear();
-                } else if let Some(hex) = hex_sends_iter.next() {
-                    hex.iter().for_each(|b| wdata.wbuf.push_escaped(*b));
-                } else if esp_test {
-                    continue;
-                } else {
-                    wdata.wbuf.push_escaped(0xFF); // dummy message type
-                    let len = normal.sample(&mut rand::thread_rng()) as usize;
-                    for i in 0..len {
-                        wdata.wbuf.push_escaped(i as u8);
+
+                    let (has_room, next_deadline) = {
+                        let window = wwindow.lock().unwrap();
+                        (window.has_room(), window.next_deadline())
+                    };
+                    let answer_pending = !alock_data.lock().unwrap().is_empty();
+                    let next_ack = wacks.lock().unwrap().next_due();
+                    let ack_due = next_ack.is_some_and(|due| due <= now);
+                    let next_send = next_ack.map_or(next_send_at, |due| next_send_at.min(due));
+                    let wait_until = match (has_room, next_deadline) {
+                        (true, _) if answer_pending => now,
+                        (true, Some(deadline)) => next_send.min(deadline),
+                        (true, None) => next_send,
+                        (false, Some(deadline)) => deadline,
+                        (false, None) => unreachable!("full window without frames"),
+                    };
+                    if wait_until > now {
+                        let started = lock.lock().unwrap();
+                        cvar.wait_timeout(started, wait_until - now).ok();
+                        continue;
+                    }
+                    if !has_room {
+                        continue;
+                    }
+                    // a push response does not take the turn of the periodic sends
+                    if answer_pending || !ack_due {
+                        next_send_at =
+                            now + Duration::from_secs(send_time_iter.next().unwrap_or(60));
                     }
                 }
-                let mut csum: u16 = 0;
-                for b in &wdata.wbuf {
-                    csum += *b as u16;
-                    csum &= 0xFF;
-                }
-                wdata.wbuf.push_escaped(csum as u8);
-                wdata.seq_no.store(seq_no, Ordering::SeqCst);
+
+                seq_no = next_seq(seq_no);
+
+                let payload = {
+                    let mut adata = alock_data.lock().unwrap();
+                    if !adata.is_empty() {
+                        std::mem::take(&mut *adata)
+                    } else if let Some(response) = wacks.lock().unwrap().pop_due(Instant::now()) {
+                        response
+                    } else if let Some(hex) = hex_sends_iter.next() {
+                        // names are resolved on every send, the registry may reload
+                        match registry::expand_names(&hex)
+                            .and_then(|h| hex::decode(h).map_err(|e| e.to_string()))
+                        {
+                            Ok(hex) => {
+                                if esp_test {
+                                    wesp.lock().unwrap().on_command(&hex);
+                                }
+                                hex
+                            }
+                            Err(e) => {
+                                error!("cannot send `{hex}`: {e}");
+                                continue;
+                            }
+                        }
+                    } else if esp_test {
+                        continue;
+                    } else {
+                        let len = normal.sample(&mut rand::thread_rng()) as usize;
+                        // dummy message type
+                        std::iter::once(0xFF).chain((0..len).map(|i| i as u8)).collect()
+                    }
+                };
+                let encoded = encode_frame(seq_no, &payload, None);
+                let (payload_len, csum) = (encoded.payload_len, encoded.csum);
+                let faulted = wfaults
+                    .lock()
+                    .unwrap()
+                    .inject(seq_no, &encoded.body, csum);
+                let wbuf = encoded.wire();
 
                 if !load_send {
-                    if wdata.wbuf.len() < 50 {
+                    let frame = &wbuf[..(wbuf.len() - 1)];
+                    if frame.len() < 50 {
                         debug!(
                             "send SEQ:{:04X} {} bytes CKSUM:{} {}",
                             seq_no,
-                            wdata.wbuf.len(),
+                            frame.len(),
                             csum,
-                            hex::encode(&wdata.wbuf),
+                            hex::encode(frame),
                         );
                     } else {
                         debug!(
                             "send SEQ:{:04X} {} bytes CKSUM:{} {} ... {}",
                             seq_no,
-                            wdata.wbuf.len(),
+                            frame.len(),
                             csum,
-                            hex::encode(&wdata.wbuf[..25]),
-                            hex::encode(&wdata.wbuf[(wdata.wbuf.len() - 25)..])
+                            hex::encode(&frame[..25]),
+                            hex::encode(&frame[(frame.len() - 25)..])
+                        );
+                        // trace!("send txt\n{}", &frame.escape_ascii().to_string());
+                        trace!("send bin\n{}", hex::encode(frame));
+                    }
+                }
+                wcounters.lock().unwrap().sent_bytes += wbuf.len();
+
+                {
+                    let mut window = wwindow.lock().unwrap();
+                    if window.contains(seq_no) {
+                        // seq wrapped onto a frame still waiting for its ACK
+                        let frame = window.remove(seq_no).unwrap();
+                        wfaults.lock().unwrap().on_abandon(seq_no);
+                        wdb.frame(frame.seq_no, frame.payload_len, frame.retries, None);
+                        wcounters.lock().unwrap().nack += 1;
+                        error!(
+                            "send SEQ:{:04X} abandoned, seq number reused after wrap",
+                            frame.seq_no
                         );
-                        // trace!("send txt\n{}", &wdata.wbuf.escape_ascii().to_string());
-                        trace!("send bin\n{}", hex::encode(&wdata.wbuf));
                     }
+                    if load_send && !window.has_room() {
+                        // no retries under load, just forget the oldest frame
+                        if let Some(frame) = window.pop_oldest() {
+                            wfaults.lock().unwrap().on_abandon(frame.seq_no);
+                            wdb.frame(frame.seq_no, frame.payload_len, frame.retries, None);
+                        }
+                    }
+                    let now = Instant::now();
+                    window.push(InFlight {
+                        seq_no,
+                        frame: wbuf.clone(),
+                        payload_len,
+                        sent_at: now,
+                        deadline: now + wpolicy.lock().unwrap().timeout(0),
+                        retries: 0,
+                    });
                 }
-                wdata.wbuf.push(AT_CMD);
-                total_sent_bytes += wdata.wbuf.len();
 
-                send_all(&mut wserial, wdata, at_cmd, load_send);
+                send_all(
+                    &mut wserial,
+                    faulted.as_ref().unwrap_or(&wbuf),
+                    at_cmd,
+                    load_send,
+                );
 
-                total_sent += 1;
+                let counters = {
+                    let mut counters = wcounters.lock().unwrap();
+                    counters.sent += 1;
+                    (counters.sent, counters.nack, counters.sent_bytes)
+                };
                 if (!load_send && seq_no % 16 == 0) || seq_no % 1024 == 0 {
+                    let inflight = wwindow.lock().unwrap().len();
+                    let latency = wlatency.lock().unwrap();
                     info!(
-                        "STATS: sent:{:05} nack:{:03} {:07}B ",
-                        total_sent, total_nack, total_sent_bytes
+                        "STATS: sent:{:05} nack:{:03} {:07}B inflight:{} RTT {} size n/p50/p99 {} RTO:{:?}",
+                        counters.0,
+                        counters.1,
+                        counters.2,
+                        inflight,
+                        latency.all(),
+                        latency.size_summary(),
+                        wpolicy.lock().unwrap().rto()
                     );
                 }
             }
         });
     }
 
+    let mut esp_log = EspLog::new(esp_log_args);
     let mut start = 0;
     let mut offset = 0;
     let mut rbuf = vec![0; MAX_BUFFER_SIZE];
@@ -281,18 +653,29 @@ pub fn test(
                                     recv_csum &= !AT_ESC_MASK as u32;
                                 }
                             }
-                            if csum & 0xFF == recv_csum {
+                            if csum & 0xFF != recv_csum {
+                                counters.lock().unwrap().csum_errors += 1;
+                                let detail = format!(
+                                    "{:02X} != {:02X} {} bytes",
+                                    csum & 0xFF,
+                                    recv_csum,
+                                    recv_size
+                                );
+                                debug!("recv checksum error {detail}");
+                                db.protocol_error("checksum", &detail);
+                            } else {
+                                counters.lock().unwrap().received += 1;
                                 if rbuf[offset] == 0
                                     && rbuf[offset + 1] == 0
                                     && rbuf[offset + 2] == 0x7E
                                 {
                                     //debug print
-                                    info!(
-                                        "{}",
-                                        &rbuf[(offset + 3)..recv_end].escape_ascii().to_string()
+                                    let source = esp_tester.lock().unwrap().local_device();
+                                    esp_log.on_debug(
+                                        &source,
+                                        &pop_all_escaped(&rbuf[(offset + 3)..recv_end]),
                                     );
                                 } else if !no_send {
-                                    let wdata = write_data.read().unwrap();
                                     let mut seq_no: u16 =
                                         pop_escaped(&rbuf[offset..recv_end], &mut offset).unwrap()
                                             as u16;
@@ -305,17 +688,12 @@ pub fn test(
                                     let _hdr_part =
                                         pop_escaped(&rbuf[offset..recv_end], &mut offset).unwrap();
 
-                                    if msg_type & 0x80 != 0
-                                        && wdata
-                                            .seq_no
-                                            .compare_exchange(
-                                                seq_no,
-                                                0,
-                                                Ordering::Acquire,
-                                                Ordering::Relaxed,
-                                            )
-                                            .is_ok()
-                                    {
+                                    let acked = if msg_type & 0x80 != 0 {
+                                        send_window.lock().unwrap().ack(seq_no)
+                                    } else {
+                                        None
+                                    };
+                                    if let Some((frame, out_of_order)) = acked {
                                         debug!(
                                             "recv-ack SEQ:{:04X} T:{:02x} {} bytes {}",
                                             seq_no,
@@ -324,8 +702,24 @@ pub fn test(
                                             hex::encode(&rbuf[offset..recv_end]),
                                         );
                                         // trace!("recv-ack bin\n{:02X?}", &rbuf[offset..recv_end]);
-                                        info!("recv ACK for {seq_no}");
+                                        let rtt = frame.sent_at.elapsed();
+                                        latency.lock().unwrap().record(frame.payload_len, rtt);
+                                        policy.lock().unwrap().on_ack(rtt, frame.retries);
+                                        faults.lock().unwrap().on_ack(seq_no, frame.retries);
+                                        db.frame(seq_no, frame.payload_len, frame.retries, Some(rtt));
+                                        if out_of_order {
+                                            info!("recv ACK for {seq_no} RTT:{rtt:?} (out of order)");
+                                        } else {
+                                            info!("recv ACK for {seq_no} RTT:{rtt:?}");
+                                        }
+                                        // wake the sender, the window has room again
+                                        cvar.notify_one();
                                     } else {
+                                        if msg_type & 0x80 != 0 {
+                                            faults.lock().unwrap().on_stray_ack(seq_no);
+                                            counters.lock().unwrap().stray_acks += 1;
+                                            db.protocol_error("stray_ack", &format!("{seq_no:04X}"));
+                                        }
                                         if i - offset < 50 {
                                             debug!(
                                                 "recv-new SEQ:{:04X} T:{:02x} {} bytes {}",
@@ -350,11 +744,9 @@ pub fn test(
                                             let mut adata = answer_data.lock().unwrap();
                                             if adata.is_empty() {
                                                 {
-                                                    adata.push_escaped(MSG_TYPE_RES_CONFIG);
-                                                    adata.push_escaped(0xFF);
-                                                    for _ in 0..12 {
-                                                        adata.push_escaped(0x00);
-                                                    }
+                                                    adata.push(MSG_TYPE_RES_CONFIG);
+                                                    adata.push(0xFF);
+                                                    adata.extend_from_slice(&[0x00; 12]);
                                                 }
                                                 let mut started = lock.lock().unwrap();
                                                 *started = true;
@@ -367,6 +759,17 @@ pub fn test(
                                         } else if esp_test {
                                             let escaped_data =
                                                 pop_all_escaped(&rbuf[offset..recv_end]);
+                                            let mut acks = push_acks.lock().unwrap();
+                                            if let Some(response) = push_response(msg_type, &escaped_data)
+                                                .filter(|_| acks.is_enabled())
+                                            {
+                                                let hex = hex::encode(&response);
+                                                let action = acks.on_push(response, Instant::now());
+                                                info!("<test> push response {hex} {action}");
+                                                // wake the sender for the response
+                                                cvar.notify_one();
+                                            }
+                                            drop(acks);
                                             let mut esp = esp_tester.lock().unwrap();
                                             esp.trace_esp_data(msg_type, &escaped_data[..]);
                                         }
@@ -381,12 +784,19 @@ pub fn test(
                                     // trace!("recv bin\n{:02X?}", &rbuf[offset..recv_end]);
                                 }
                             }
+                        } else {
+                            counters.lock().unwrap().short_frames += 1;
+                            db.protocol_error("short", &hex::encode(&rbuf[offset..i]));
                         }
                     }
                     offset = i + 1;
                 }
             }
             start += n;
+            if offset != start && start >= RESET_BUFFER_SIZE {
+                counters.lock().unwrap().overruns += 1;
+                db.protocol_error("overrun", &format!("{} bytes", start - offset));
+            }
             if offset == start || start >= RESET_BUFFER_SIZE {
                 start = 0;
                 offset = 0;
@@ -397,13 +807,122 @@ pub fn test(
     }
 }
 
+/// mesh table and serial totals, printed and logged
+fn log_summary(counters: &Mutex<SerialCounters>, esp_tester: Option<&Mutex<EspTester>>) {
+    let mut lines = esp_tester.map_or_else(Vec::new, |esp| esp.lock().unwrap().summary());
+    let c = counters.lock().unwrap();
+    lines.push(format!(
+        "sent:{} nack:{} {}B received:{} csum errors:{} short:{} overruns:{} stray ACKs:{}",
+        c.sent,
+        c.nack,
+        c.sent_bytes,
+        c.received,
+        c.csum_errors,
+        c.short_frames,
+        c.overruns,
+        c.stray_acks
+    ));
+    for line in lines {
+        println!("{line}");
+        info!("SUMMARY: {line}");
+    }
+}
+
+fn export_topology(esp_tester: &Mutex<EspTester>, args: &TopologyArgs) {
+    let topology = esp_tester
+        .lock()
+        .unwrap()
+        .topology(Duration::from_secs(args.silent_after));
+    for cycle in &topology.loops {
+        error!("TOPOLOGY: routing loop {}", cycle.join(" -> "));
+    }
+    for node in topology.nodes_with(Issue::Orphan) {
+        warn!("TOPOLOGY: {} has no route to a coordinator", node.name);
+    }
+    for node in topology.nodes_with(Issue::SilentNextHop) {
+        warn!(
+            "TOPOLOGY: {} routes through silent {}",
+            node.name,
+            node.next_hop.as_deref().unwrap_or_default()
+        );
+    }
+    match &args.topology {
+        Some(prefix) => match topology.export(prefix) {
+            Ok(()) => info!(
+                "TOPOLOGY: {} nodes written to {}.dot/.json",
+                topology.nodes.len(),
+                prefix.display()
+            ),
+            Err(e) => error!("TOPOLOGY: cannot write {}: {e}", prefix.display()),
+        },
+        None => println!("{}", topology.to_dot()),
+    }
+    export_neighbours(esp_tester, args);
+}
+
+/// broadcast a neighbour query through the coordinator, log and export the replies later
+fn query_neighbours(
+    esp_tester: &Arc<Mutex<EspTester>>,
+    answer_data: &Mutex<UartVec>,
+    pair: &(Mutex<bool>, Condvar),
+    args: &TopologyArgs,
+) {
+    {
+        let mut adata = answer_data.lock().unwrap();
+        if !adata.is_empty() {
+            warn!("NEIGHBOURS: cannot send the query because data queue not empty!");
+            return;
+        }
+        *adata = esp_tester.lock().unwrap().neighbour_query();
+    }
+    let (lock, cvar) = pair;
+    let _started = lock.lock().unwrap();
+    cvar.notify_one();
+    info!("NEIGHBOURS: query sent, collecting updates for {NEIGH_COLLECT:?}");
+    let esp_tester = esp_tester.clone();
+    let args = args.clone();
+    thread::spawn(move || {
+        sleep(NEIGH_COLLECT);
+        esp_tester.lock().unwrap().log_neighbours();
+        export_neighbours(&esp_tester, &args);
+    });
+}
+
+fn export_neighbours(esp_tester: &Mutex<EspTester>, args: &TopologyArgs) {
+    let Some(prefix) = &args.topology else {
+        return;
+    };
+    let esp_tester = esp_tester.lock().unwrap();
+    let neighbours = esp_tester.neighbours();
+    if neighbours.is_empty() {
+        return;
+    }
+    match neighbours.export(prefix) {
+        Ok(()) => info!(
+            "NEIGHBOURS: matrix written to {}.neighbours.csv/.json",
+            prefix.display()
+        ),
+        Err(e) => error!("NEIGHBOURS: cannot write {}: {e}", prefix.display()),
+    }
+}
+
+fn export_calls(esp_tester: &Mutex<EspTester>, args: &CallArgs) {
+    let Some(path) = &args.calls else {
+        return;
+    };
+    match esp_tester.lock().unwrap().calls().export(path) {
+        Ok(()) => info!("CALLS: timeline written to {}", path.display()),
+        Err(e) => error!("CALLS: cannot write {}: {e}", path.display()),
+    }
+}
+
 fn send_all(
     wserial: &mut Box<dyn serialport::SerialPort>,
-    wdata: std::sync::RwLockWriteGuard<'_, WriteData>,
+    wbuf: &[u8],
     at_cmd: bool,
     load_send: bool,
 ) {
-    wserial.write_all(&wdata.wbuf).ok();
+    wserial.write_all(wbuf).ok();
     wserial.flush().ok();
 
     #[cfg(feature = "async")]
@@ -413,7 +932,7 @@ fn send_all(
         wserial.flush().ok();
         sleep(Duration::from_millis(200));
         let mut repeat_at_cmd = 1;
-        while wserial.bytes_to_read().unwrap() == 0 && repeat_at_cmd < wdata.wbuf.len() / 100 + 3 {
+        while wserial.bytes_to_read().unwrap() == 0 && repeat_at_cmd < wbuf.len() / 100 + 3 {
             repeat_at_cmd += 1;
             sleep(Duration::from_millis(200));
 
@@ -426,52 +945,6 @@ fn send_all(
     }
 }
 
-pub(crate) fn generate(length: usize) {
-    let mut csum: u32 = 0;
-    for i in 1..length {
-        print!("{}", i % 10);
-        csum += 0x30 + (i % 10) as u32;
-        csum &= 0xff;
-    }
-    while !(csum as u8).is_ascii_alphanumeric() {
-        print!("0");
-        csum += 0x30;
-        csum &= 0xff;
-    }
-    println!("{}", (csum as u8) as char);
-}
-
-pub(crate) fn generate_bin(length: usize, checksum: Option<u8>) {
-    let mut wbuf = UartVec::with_capacity(length * 2);
-    let seq_no = 1;
-    let b = (seq_no >> 8) as u8;
-    wbuf.push_escaped(b);
-    let b = (seq_no) as u8;
-    wbuf.push_escaped(b);
-    wbuf.push_escaped(0xFF); // dummy message type
-    for i in 0..length {
-        wbuf.push_escaped(i as u8);
-    }
-    let mut csum: u32 = 0;
-    for b in &wbuf {
-        csum += *b as u32;
-        csum &= 0xFF;
-        debug!("{:02x} -> {:02x}", *b, csum);
-    }
-    if let Some(cs) = checksum {
-        while csum != cs as u32 {
-            let b = 1;
-            wbuf.push_escaped(b);
-            csum += b as u32;
-            csum &= 0xFF;
-            debug!("{:02x} -> {:02x}", b, csum);
-        }
-    }
-    wbuf.push_escaped(csum as u8);
-    wbuf.push(AT_CMD);
-    println!("{}", hex::encode(wbuf));
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -530,5 +1003,24 @@ mod test {
         }
         assert_eq!(start, 0);
         assert_eq!(offset, 0);
+        // the same frames through decode_frame
+        let frames: Vec<Frame> = rbuf
+            .split(|b| *b == AT_CMD)
+            .filter_map(decode_frame)
+            .collect();
+        assert_eq!(frames.len(), msg);
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let payload = [0x41, 0, AT_CMD, AT_ESC, 7];
+        let wire = encode_frame(0x1234, &payload, Some(AT_CMD)).wire();
+        let frame = decode_frame(&wire[..(wire.len() - 1)]).unwrap();
+        assert_eq!((frame.seq_no, frame.msg_type, frame.part), (0x1234, 0x41, 0));
+        assert_eq!(&frame.data[..3], &[AT_CMD, AT_ESC, 7]);
+        assert!(!frame.is_ack());
+        let mut bad = wire[..(wire.len() - 1)].to_vec();
+        bad[2] ^= 1;
+        assert_eq!(decode_frame(&bad), None);
     }
 }
@@ -0,0 +1,283 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Args;
+use serde::Serialize;
+
+use crate::registry::{self, Mac};
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct TopologyArgs {
+    /// write the mesh graph to <TOPOLOGY>.dot and <TOPOLOGY>.json on demand and at exit
+    #[arg(long)]
+    pub topology: Option<PathBuf>,
+    /// seconds without NETSTAT before a next hop counts as silent
+    #[arg(long, default_value_t = 300)]
+    pub silent_after: u64,
+}
+
+/// what the tester knows about a device, input to the graph
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NodeInput {
+    pub mac: Mac,
+    pub coordinator: bool,
+    pub next_hop: Option<Mac>,
+    pub rssi: Option<u8>,
+    pub snr: Option<u8>,
+    pub last_seen: Duration,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Issue {
+    // next hops lead back to this node
+    Loop,
+    // no route to a coordinator
+    Orphan,
+    // the next hop stopped sending NETSTAT
+    SilentNextHop,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Node {
+    pub mac: String,
+    pub name: String,
+    pub coordinator: bool,
+    pub next_hop: Option<String>,
+    pub hops: Option<u32>,
+    // the coordinator the route ends at
+    pub root: Option<String>,
+    pub rssi: Option<u8>,
+    pub snr: Option<u8>,
+    // None for next hops never heard from
+    pub last_seen_secs: Option<u64>,
+    pub issues: Vec<Issue>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Topology {
+    pub nodes: Vec<Node>,
+    pub loops: Vec<Vec<String>>,
+}
+
+fn display_name(mac: &Mac) -> String {
+    registry::name(mac).unwrap_or_else(|| hex::encode(mac))
+}
+
+fn is_coordinator_role(mac: &Mac) -> bool {
+    registry::get(mac).is_some_and(|d| d.role.eq_ignore_ascii_case("coordinator"))
+}
+
+impl Topology {
+    pub fn build(inputs: impl IntoIterator<Item = NodeInput>, silent_after: Duration) -> Self {
+        let mut seen: BTreeMap<Mac, NodeInput> = inputs.into_iter().map(|n| (n.mac, n)).collect();
+        // next hops never heard from still belong in the graph
+        let unknown: Vec<Mac> = seen
+            .values()
+            .filter_map(|n| n.next_hop)
+            .filter(|mac| !seen.contains_key(mac))
+            .collect();
+        let heard: HashSet<Mac> = seen.keys().copied().collect();
+        for mac in unknown {
+            seen.entry(mac).or_insert(NodeInput {
+                mac,
+                ..Default::default()
+            });
+        }
+        let coordinator = |n: &NodeInput| n.coordinator || is_coordinator_role(&n.mac);
+
+        let mut topology = Topology::default();
+        let mut in_loop: HashSet<Mac> = HashSet::new();
+        for start in seen.values() {
+            // walk towards the coordinator
+            let mut path = vec![start.mac];
+            let mut hops = None;
+            let mut root = None;
+            let mut cur = start;
+            loop {
+                if coordinator(cur) {
+                    hops = Some(path.len() as u32 - 1);
+                    root = Some(hex::encode(cur.mac));
+                    break;
+                }
+                let Some(next) = cur.next_hop.and_then(|mac| seen.get(&mac)) else {
+                    break;
+                };
+                if let Some(pos) = path.iter().position(|mac| *mac == next.mac) {
+                    let cycle = &path[pos..];
+                    if cycle.iter().all(|mac| !in_loop.contains(mac)) {
+                        in_loop.extend(cycle.iter().copied());
+                        topology.loops.push(cycle.iter().map(hex::encode).collect());
+                    }
+                    break;
+                }
+                path.push(next.mac);
+                cur = next;
+            }
+
+            let mut issues = Vec::new();
+            if in_loop.contains(&start.mac) {
+                issues.push(Issue::Loop);
+            } else if hops.is_none() {
+                issues.push(Issue::Orphan);
+            }
+            if let Some(next) = start.next_hop.and_then(|mac| seen.get(&mac)) {
+                if heard.contains(&next.mac) && next.last_seen > silent_after {
+                    issues.push(Issue::SilentNextHop);
+                }
+            }
+            topology.nodes.push(Node {
+                mac: hex::encode(start.mac),
+                name: display_name(&start.mac),
+                coordinator: coordinator(start),
+                next_hop: start.next_hop.map(hex::encode),
+                hops,
+                root,
+                rssi: start.rssi,
+                snr: start.snr,
+                last_seen_secs: heard
+                    .contains(&start.mac)
+                    .then_some(start.last_seen.as_secs()),
+                issues,
+            });
+        }
+        topology
+    }
+
+    pub fn nodes_with(&self, issue: Issue) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().filter(move |n| n.issues.contains(&issue))
+    }
+
+    /// number of devices routing through each node on their way to a coordinator
+    pub fn downstream(&self) -> BTreeMap<String, usize> {
+        let next: BTreeMap<&str, &str> = self
+            .nodes
+            .iter()
+            .filter(|n| !n.coordinator)
+            .filter_map(|n| Some((n.mac.as_str(), n.next_hop.as_deref()?)))
+            .collect();
+        let mut downstream = BTreeMap::new();
+        // only routed nodes, the walk of the others may never end
+        for node in self.nodes.iter().filter(|n| n.hops.is_some()) {
+            let mut cur = node.mac.as_str();
+            while let Some(hop) = next.get(cur) {
+                *downstream.entry(hop.to_string()).or_default() += 1;
+                cur = hop;
+            }
+        }
+        downstream
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph mesh {\n    rankdir=BT;\n");
+        for node in &self.nodes {
+            let mut label = node.name.clone();
+            if let Some(hops) = node.hops {
+                let _ = write!(label, "\\nhops:{hops}");
+            }
+            let mut attrs = vec![format!("label=\"{label}\"")];
+            if node.coordinator {
+                attrs.push("shape=doublecircle".into());
+            }
+            if node.last_seen_secs.is_none() {
+                attrs.push("style=dashed".into());
+            }
+            if node.issues.contains(&Issue::Loop) {
+                attrs.push("color=red".into());
+            } else if node.issues.contains(&Issue::Orphan) {
+                attrs.push("color=orange".into());
+            }
+            let _ = writeln!(dot, "    \"{}\" [{}];", node.mac, attrs.join(", "));
+        }
+        for node in &self.nodes {
+            let Some(next) = &node.next_hop else {
+                continue;
+            };
+            let mut attrs = Vec::new();
+            if let (Some(rssi), Some(snr)) = (node.rssi, node.snr) {
+                attrs.push(format!("label=\"rssi:{rssi} snr:{snr}\""));
+            }
+            if node.issues.contains(&Issue::SilentNextHop) {
+                attrs.push("style=dashed, color=gray".into());
+            }
+            let _ = writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [{}];",
+                node.mac,
+                next,
+                attrs.join(", ")
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn export(&self, prefix: &Path) -> std::io::Result<()> {
+        std::fs::write(prefix.with_extension("dot"), self.to_dot())?;
+        std::fs::write(prefix.with_extension("json"), self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(id: u8, coordinator: bool, next_hop: Option<u8>, last_seen: u64) -> NodeInput {
+        NodeInput {
+            mac: [0, 0, 0, 0, 0, id],
+            coordinator,
+            next_hop: next_hop.map(|n| [0, 0, 0, 0, 0, n]),
+            rssi: Some(216),
+            snr: Some(10),
+            last_seen: Duration::from_secs(last_seen),
+        }
+    }
+
+    #[test]
+    fn test_hops_loops_orphans() {
+        let topology = Topology::build(
+            [
+                node(1, true, None, 1),
+                node(2, false, Some(1), 1),
+                node(3, false, Some(2), 1),
+                // 4 <-> 5 loop, 6 hangs off the loop
+                node(4, false, Some(5), 1),
+                node(5, false, Some(4), 1),
+                node(6, false, Some(4), 1),
+                // 7 routes through 8, never heard from
+                node(7, false, Some(8), 1),
+                // 9 routes through 10, silent for a long time
+                node(9, false, Some(10), 1),
+                node(10, false, Some(1), 1000),
+            ],
+            Duration::from_secs(300),
+        );
+        let by_id = |id: u8| {
+            let mac = hex::encode([0, 0, 0, 0, 0, id]);
+            topology.nodes.iter().find(|n| n.mac == mac).unwrap()
+        };
+        assert_eq!(by_id(1).hops, Some(0));
+        assert_eq!(by_id(3).hops, Some(2));
+        assert_eq!(by_id(3).root, Some(by_id(1).mac.clone()));
+        assert_eq!(topology.loops.len(), 1);
+        assert_eq!(by_id(4).issues, vec![Issue::Loop]);
+        assert_eq!(by_id(6).issues, vec![Issue::Orphan]);
+        assert_eq!(by_id(7).issues, vec![Issue::Orphan]);
+        assert_eq!(by_id(8).last_seen_secs, None);
+        assert_eq!(by_id(9).issues, vec![Issue::SilentNextHop]);
+        assert_eq!(by_id(9).hops, Some(2));
+        assert!(topology.to_dot().contains("-> \"00000000000a\""));
+        let downstream = topology.downstream();
+        assert_eq!(downstream[&by_id(1).mac], 4);
+        assert_eq!(downstream[&by_id(2).mac], 1);
+        assert!(!downstream.contains_key(&by_id(4).mac));
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+use clap::Args;
+
+// weight of a new sample in the learned NETSTAT period
+const PERIOD_ALPHA: f64 = 1.0 / 8.0;
+/// how often the watchdog looks at the devices
+pub(crate) const WATCHDOG_TICK: Duration = Duration::from_secs(1);
+
+#[derive(Args, Clone, Copy, Debug)]
+pub struct WatchdogArgs {
+    /// expected NETSTAT period in seconds, learned per device when not set
+    #[arg(long)]
+    pub netstat_period: Option<u64>,
+    /// missed NETSTAT periods before a device is reported stale
+    #[arg(long, default_value_t = 3)]
+    pub stale_periods: u32,
+    /// missed NETSTAT periods before a device is reported offline
+    #[arg(long, default_value_t = 10)]
+    pub offline_periods: u32,
+    /// silent devices behind one next node reported as a partition
+    #[arg(long, default_value_t = 3)]
+    pub partition_size: usize,
+}
+
+impl Default for WatchdogArgs {
+    fn default() -> Self {
+        Self {
+            netstat_period: None,
+            stale_periods: 3,
+            offline_periods: 10,
+            partition_size: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Health {
+    #[default]
+    Online,
+    Stale,
+    Offline,
+}
+
+/// NETSTAT period and online state of one device
+#[derive(Debug, Default)]
+pub(crate) struct Liveness {
+    learned: Option<f64>,
+    last_netstat: Option<Instant>,
+    health: Health,
+}
+
+impl Liveness {
+    /// a new NETSTAT, `periods` is 1 + the NETSTATs lost before it
+    pub fn on_netstat(&mut self, now: Instant, periods: u16) {
+        if let Some(last) = self.last_netstat {
+            // the silence of an outage says nothing about the period
+            if self.health == Health::Online {
+                let sample = now.saturating_duration_since(last).as_secs_f64() / periods as f64;
+                self.learned = Some(match self.learned {
+                    Some(p) => (1.0 - PERIOD_ALPHA) * p + PERIOD_ALPHA * sample,
+                    None => sample,
+                });
+            }
+        }
+        self.last_netstat = Some(now);
+    }
+
+    /// any traffic from the device, returns the state it recovered from
+    pub fn on_seen(&mut self) -> Option<Health> {
+        match std::mem::take(&mut self.health) {
+            Health::Online => None,
+            health => {
+                self.last_netstat = None;
+                Some(health)
+            }
+        }
+    }
+
+    pub fn period(&self, args: &WatchdogArgs) -> Option<Duration> {
+        match args.netstat_period {
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => self.learned.map(Duration::from_secs_f64),
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health
+    }
+
+    /// re-evaluate after `silent` without traffic, returns the new state when it changed
+    pub fn check(&mut self, silent: Duration, args: &WatchdogArgs) -> Option<Health> {
+        let period = self.period(args)?;
+        let health = if silent > period * args.offline_periods {
+            Health::Offline
+        } else if silent > period * args.stale_periods {
+            Health::Stale
+        } else {
+            // back online is reported by on_seen with the outage
+            return None;
+        };
+        if health == self.health {
+            return None;
+        }
+        self.health = health;
+        Some(health)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_learn_and_check() {
+        let args = WatchdogArgs::default();
+        let mut live = Liveness::default();
+        let t0 = Instant::now();
+        assert_eq!(live.check(Duration::from_secs(1000), &args), None);
+        live.on_netstat(t0, 1);
+        live.on_netstat(t0 + Duration::from_secs(10), 1);
+        // one NETSTAT lost in between
+        live.on_netstat(t0 + Duration::from_secs(30), 2);
+        assert_eq!(live.period(&args), Some(Duration::from_secs(10)));
+
+        assert_eq!(live.check(Duration::from_secs(20), &args), None);
+        assert_eq!(
+            live.check(Duration::from_secs(31), &args),
+            Some(Health::Stale)
+        );
+        assert_eq!(live.check(Duration::from_secs(40), &args), None);
+        assert_eq!(
+            live.check(Duration::from_secs(101), &args),
+            Some(Health::Offline)
+        );
+        assert_eq!(live.on_seen(), Some(Health::Offline));
+        assert_eq!(live.health(), Health::Online);
+        assert_eq!(live.on_seen(), None);
+
+        let fixed = WatchdogArgs {
+            netstat_period: Some(60),
+            ..args
+        };
+        assert_eq!(live.period(&fixed), Some(Duration::from_secs(60)));
+    }
+}
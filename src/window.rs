@@ -0,0 +1,119 @@
+use std::{collections::VecDeque, time::Instant};
+
+use crate::seq::seq_diff;
+
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    pub seq_no: u16,
+    // escaped frame as written to the port, including checksum and AT_CMD
+    pub frame: Vec<u8>,
+    pub payload_len: usize,
+    // first send, kept across retries
+    pub sent_at: Instant,
+    pub deadline: Instant,
+    pub retries: u32,
+}
+
+/// Frames sent and not yet ACKed, oldest first
+#[derive(Debug)]
+pub(crate) struct SendWindow {
+    size: usize,
+    frames: VecDeque<InFlight>,
+}
+
+impl SendWindow {
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            size,
+            frames: VecDeque::with_capacity(size),
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.frames.len() < self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn contains(&self, seq_no: u16) -> bool {
+        self.frames.iter().any(|f| f.seq_no == seq_no)
+    }
+
+    pub fn push(&mut self, frame: InFlight) {
+        self.frames.push_back(frame);
+    }
+
+    pub fn pop_oldest(&mut self) -> Option<InFlight> {
+        self.frames.pop_front()
+    }
+
+    /// remove the ACKed frame; the flag is set when older frames are still outstanding
+    pub fn ack(&mut self, seq_no: u16) -> Option<(InFlight, bool)> {
+        let oldest = self.frames.front()?.seq_no;
+        let pos = self.frames.iter().position(|f| f.seq_no == seq_no)?;
+        let frame = self.frames.remove(pos)?;
+        Some((frame, seq_diff(oldest, seq_no) > 0))
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.frames.iter().map(|f| f.deadline).min()
+    }
+
+    /// seq numbers of the frames whose retransmission timer expired
+    pub fn expired(&self, now: Instant) -> Vec<u16> {
+        self.frames
+            .iter()
+            .filter(|f| f.deadline <= now)
+            .map(|f| f.seq_no)
+            .collect()
+    }
+
+    pub fn get_mut(&mut self, seq_no: u16) -> Option<&mut InFlight> {
+        self.frames.iter_mut().find(|f| f.seq_no == seq_no)
+    }
+
+    pub fn remove(&mut self, seq_no: u16) -> Option<InFlight> {
+        let pos = self.frames.iter().position(|f| f.seq_no == seq_no)?;
+        self.frames.remove(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::seq::next_seq;
+
+    fn in_flight(seq_no: u16) -> InFlight {
+        InFlight {
+            seq_no,
+            frame: vec![],
+            payload_len: 0,
+            sent_at: Instant::now(),
+            deadline: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_ack_across_wrap() {
+        let mut window = SendWindow::new(3);
+        let mut seq_no = 0xFFFE;
+        for _ in 0..3 {
+            window.push(in_flight(seq_no));
+            seq_no = next_seq(seq_no);
+        }
+        assert!(!window.has_room());
+        // 0xFFFE, 0xFFFF, 0x0001 outstanding
+        let (frame, out_of_order) = window.ack(0x0001).unwrap();
+        assert_eq!(frame.seq_no, 0x0001);
+        assert!(out_of_order);
+        assert!(window.ack(0x0001).is_none());
+        let (_, out_of_order) = window.ack(0xFFFE).unwrap();
+        assert!(!out_of_order);
+        assert_eq!(window.len(), 1);
+        assert!(window.has_room());
+    }
+}